@@ -0,0 +1,356 @@
+use std::error::Error;
+
+use serde::Deserialize;
+
+use crate::tls_transport::TlsFiles;
+
+/// What to poll and for how long.
+///
+/// Read from a TOML file, or a JSON one when its name ends with `.json`
+/// (`--config FILE`); see `config.example.toml`. Without a file, the
+/// positional arguments `[ADDRESS] [PORT] [DATABASE]` describe a single
+/// target as before.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// SQLite database receiving the events
+    #[serde(default = "default_database")]
+    pub database: String,
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    /// stop after this long, or run until interrupted if not set
+    pub duration_ms: Option<u64>,
+    /// longest wait for a connection or a response
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// first wait before reconnecting to a device, doubled after every
+    /// failed attempt up to `reconnect_max_delay_ms`
+    #[serde(default = "default_reconnect_delay_ms")]
+    pub reconnect_delay_ms: u64,
+    #[serde(default = "default_reconnect_max_delay_ms")]
+    pub reconnect_max_delay_ms: u64,
+    pub targets: Vec<Target>,
+}
+
+/// Device to poll.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    /// stored with the events, `address:port/unit_id` if not set
+    pub name: Option<String>,
+    pub address: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_unit_id")]
+    pub unit_id: u8,
+    pub coils: Option<Area>,
+    pub discrete_inputs: Option<Area>,
+    pub input_registers: Option<Area>,
+    pub holding_registers: Option<Area>,
+    /// certificates for Modbus/TCP Security, plain Modbus/TCP if not set
+    pub tls: Option<TlsFiles>,
+}
+
+/// Range of addresses read at every poll.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Area {
+    #[serde(default)]
+    pub start: u16,
+    pub quantity: u16,
+}
+
+fn default_database() -> String {
+    "plc.db".to_owned()
+}
+
+fn default_poll_interval_ms() -> u64 {
+    50
+}
+
+fn default_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_reconnect_delay_ms() -> u64 {
+    100
+}
+
+fn default_reconnect_max_delay_ms() -> u64 {
+    10_000
+}
+
+fn default_port() -> u16 {
+    502
+}
+
+fn default_unit_id() -> u8 {
+    1
+}
+
+const USAGE: &str = "\
+usage: modbus_client --config FILE
+       modbus_client [ADDRESS] [PORT] [DATABASE]
+                     [--tls-cert FILE --tls-key FILE --tls-ca FILE]";
+
+impl Config {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("cannot read {path:?}: {e}"))?;
+        let config: Config = if path.ends_with(".json") {
+            serde_json::from_str(&text).map_err(|e| format!("invalid config {path:?}: {e}"))?
+        } else {
+            toml::from_str(&text).map_err(|e| format!("invalid config {path:?}: {e}"))?
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut positional = Vec::new();
+        let mut config_file = None;
+        let (mut tls_cert, mut tls_key, mut tls_ca) = (None, None, None);
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let option = match arg.as_str() {
+                "--config" => &mut config_file,
+                "--tls-cert" => &mut tls_cert,
+                "--tls-key" => &mut tls_key,
+                "--tls-ca" => &mut tls_ca,
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                _ if arg.starts_with("--") => {
+                    Err(format!("unexpected argument {arg:?}, see --help"))?
+                }
+                _ => {
+                    positional.push(arg);
+                    continue;
+                }
+            };
+            *option = Some(args.next().ok_or(format!("missing value for {arg}"))?);
+        }
+        let tls = match (tls_cert, tls_key, tls_ca) {
+            (Some(cert), Some(key), Some(ca)) => Some(TlsFiles { cert, key, ca }),
+            (None, None, None) => None,
+            _ => Err("TLS needs --tls-cert, --tls-key and --tls-ca")?,
+        };
+        if let Some(path) = config_file {
+            if !positional.is_empty() || tls.is_some() {
+                Err("--config cannot be combined with other arguments")?
+            }
+            return Config::load(&path);
+        }
+
+        let address = positional
+            .first()
+            .cloned()
+            .unwrap_or("127.0.0.1".to_owned());
+        let port = match positional.get(1) {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("invalid port number {port:?}"))?,
+            None => 55022,
+        };
+        let database = positional.get(2).cloned().unwrap_or_else(default_database);
+        // real devices have larger areas than the simulator
+        let (coils_quantity, holding_registers_quantity, duration_ms) = match port {
+            502 | 802 => (256, 125, 600 * 1000),
+            _ => (20, 5, 20 * 1000),
+        };
+        let config = Config {
+            database,
+            poll_interval_ms: default_poll_interval_ms(),
+            duration_ms: Some(duration_ms),
+            timeout_ms: default_timeout_ms(),
+            reconnect_delay_ms: default_reconnect_delay_ms(),
+            reconnect_max_delay_ms: default_reconnect_max_delay_ms(),
+            targets: vec![Target {
+                name: None,
+                address,
+                port,
+                unit_id: default_unit_id(),
+                coils: Some(Area {
+                    start: 0,
+                    quantity: coils_quantity,
+                }),
+                discrete_inputs: None,
+                input_registers: None,
+                holding_registers: Some(Area {
+                    start: 0,
+                    quantity: holding_registers_quantity,
+                }),
+                tls,
+            }],
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.poll_interval_ms == 0 {
+            Err("the poll interval must be at least 1 ms")?
+        }
+        if self.timeout_ms == 0 {
+            Err("the timeout must be at least 1 ms")?
+        }
+        if self.reconnect_delay_ms == 0 || self.reconnect_max_delay_ms < self.reconnect_delay_ms {
+            Err(
+                "the reconnect delays must satisfy 0 < reconnect_delay_ms <= reconnect_max_delay_ms",
+            )?
+        }
+        if self.targets.is_empty() {
+            Err("at least one target must be configured")?
+        }
+        let mut devices = std::collections::BTreeSet::new();
+        for target in &self.targets {
+            target.validate()?;
+            if !devices.insert(target.device()) {
+                Err(format!("several targets are named {:?}", target.device()))?
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Target {
+    pub fn device(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("{}:{}/{}", self.address, self.port, self.unit_id),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        // largest quantities a single Modbus request may read
+        let areas = [
+            ("coils", self.coils, 2000),
+            ("discrete_inputs", self.discrete_inputs, 2000),
+            ("input_registers", self.input_registers, 125),
+            ("holding_registers", self.holding_registers, 125),
+        ];
+        for (name, area, max_quantity) in areas {
+            let Some(area) = area else {
+                continue;
+            };
+            if !(1..=max_quantity).contains(&area.quantity) {
+                Err(format!(
+                    "{}: {name} quantity must be between 1 and {max_quantity}",
+                    self.address
+                ))?
+            }
+            if u32::from(area.start) + u32::from(area.quantity) > 0x10000 {
+                Err(format!("{}: {name} go past address 65535", self.address))?
+            }
+        }
+        if areas.iter().all(|(_, area, _)| area.is_none()) {
+            Err(format!("{}: nothing to poll", self.address))?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_toml(text: &str) -> Result<Config, Box<dyn Error>> {
+        let config: Config = toml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_json(text: &str) -> Result<Config, Box<dyn Error>> {
+        let config: Config = serde_json::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn toml_target() {
+        let config = from_toml(
+            r#"
+            duration_ms = 5000
+            [[targets]]
+            address = "10.0.0.2"
+            unit_id = 7
+            holding_registers = { start = 100, quantity = 10 }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.poll_interval_ms, 50);
+        assert_eq!(config.duration_ms, Some(5000));
+        let target = &config.targets[0];
+        assert_eq!((target.port, target.unit_id), (502, 7));
+        assert_eq!(target.device(), "10.0.0.2:502/7");
+        let area = target.holding_registers.unwrap();
+        assert_eq!((area.start, area.quantity), (100, 10));
+        assert!(target.coils.is_none());
+    }
+
+    #[test]
+    fn json_target() {
+        let config = from_json(
+            r#"{
+                "poll_interval_ms": 20,
+                "targets": [{
+                    "name": "pump",
+                    "address": "10.0.0.3",
+                    "port": 5020,
+                    "coils": { "quantity": 8 }
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.poll_interval_ms, 20);
+        assert_eq!(config.duration_ms, None);
+        let target = &config.targets[0];
+        assert_eq!((target.port, target.unit_id), (5020, 1));
+        assert_eq!(target.device(), "pump");
+        assert_eq!(target.coils.unwrap().start, 0);
+    }
+
+    #[test]
+    fn area_end() {
+        let target = |start, quantity| {
+            format!(
+                "[[targets]]\naddress = \"10.0.0.2\"\n\
+                 input_registers = {{ start = {start}, quantity = {quantity} }}"
+            )
+        };
+        // the last register is 65535
+        assert!(from_toml(&target(65535, 1)).is_ok());
+        assert!(from_toml(&target(65411, 125)).is_ok());
+        assert!(from_toml(&target(65535, 2)).is_err());
+        assert!(from_toml(&target(65412, 125)).is_err());
+        assert!(from_toml(&target(0, 0)).is_err());
+        assert!(from_toml(&target(0, 126)).is_err());
+    }
+
+    #[test]
+    fn poll_interval() {
+        let target = "[[targets]]\naddress = \"10.0.0.2\"\ncoils = { quantity = 1 }";
+        let config = from_toml(target).unwrap();
+        assert_eq!(config.poll_interval_ms, default_poll_interval_ms());
+        assert!(from_toml(&format!("poll_interval_ms = 0\n{target}")).is_err());
+        assert!(from_json(r#"{"poll_interval_ms": 0, "targets": []}"#).is_err());
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(from_toml("targets = []").is_err());
+        assert!(from_toml("[[targets]]\naddress = \"10.0.0.2\"").is_err());
+        // unknown fields are not ignored
+        assert!(
+            from_toml("[[targets]]\naddress = \"10.0.0.2\"\nunit = 3\ncoils = { quantity = 1 }")
+                .is_err()
+        );
+        assert!(
+            from_toml(
+                "[[targets]]\naddress = \"10.0.0.2\"\nunit_id = 256\ncoils = { quantity = 1 }"
+            )
+            .is_err()
+        );
+    }
+}
@@ -54,9 +54,48 @@ pub fn print_coils_and_holding_registers(coils: &[Coil], holding_registers: &[u1
 //     }
 // }
 
-pub fn detect_coil_events(
+/// Modbus data table, stored in the `area` column.
+#[derive(Debug, Clone, Copy)]
+pub enum Table {
+    Coils,
+    DiscreteInputs,
+    InputRegisters,
+    HoldingRegisters,
+    /// not a Modbus table: the link to the device, 1 while connected
+    Connection,
+}
+
+impl Table {
+    fn area(self) -> &'static str {
+        match self {
+            Table::Coils => "coil",
+            Table::DiscreteInputs => "discrete_input",
+            Table::InputRegisters => "input_register",
+            Table::HoldingRegisters => "holding_register",
+            Table::Connection => "connection",
+        }
+    }
+}
+
+/// The device was lost or reached again.
+pub fn connection_event(utc_ms: u64, connected: bool) -> Event {
+    Event {
+        utc_ms,
+        table: Table::Connection,
+        address: None,
+        previous: !connected as u16,
+        state: connected as u16,
+        latency_us: None,
+    }
+}
+
+/// Events of coils or discrete inputs.
+pub fn detect_bit_events(
     events: &mut Vec<Event>,
     utc_ms: u64,
+    latency_us: u32,
+    table: Table,
+    start: u16,
     prev_values: &[Coil],
     new_values: &[Coil],
 ) {
@@ -64,12 +103,11 @@ pub fn detect_coil_events(
         if *prev != *new {
             let event = Event {
                 utc_ms,
-                coil: true,
-                address: index as u16,
-                state: match new {
-                    Coil::On => 1,
-                    Coil::Off => 0,
-                },
+                table,
+                address: Some(start + index as u16),
+                previous: (*prev == Coil::On) as u16,
+                state: (*new == Coil::On) as u16,
+                latency_us: Some(latency_us),
             };
 
             events.push(event);
@@ -77,9 +115,13 @@ pub fn detect_coil_events(
     }
 }
 
-pub fn detect_holding_events(
+/// Events of holding or input registers.
+pub fn detect_register_events(
     events: &mut Vec<Event>,
     utc_ms: u64,
+    latency_us: u32,
+    table: Table,
+    start: u16,
     prev_values: &[u16],
     new_values: &[u16],
 ) {
@@ -87,9 +129,11 @@ pub fn detect_holding_events(
         if *prev != *new {
             let event = Event {
                 utc_ms,
-                coil: false,
-                address: index as u16,
+                table,
+                address: Some(start + index as u16),
+                previous: *prev,
                 state: *new,
+                latency_us: Some(latency_us),
             };
 
             events.push(event);
@@ -99,22 +143,43 @@ pub fn detect_holding_events(
 
 pub struct Event {
     utc_ms: u64,
-    coil: bool,
-    address: u16,
+    table: Table,
+    address: Option<u16>,
+    previous: u16,
     state: u16,
+    /// time taken to read the sample showing the change
+    latency_us: Option<u32>,
+}
+
+/// Events of a device, stored together.
+pub struct Batch {
+    pub device: String,
+    pub unit_id: u8,
+    pub events: Vec<Event>,
 }
 
 pub fn store_events(
     db: &rusqlite::Connection,
-    channel_receiver: std::sync::mpsc::Receiver<Vec<Event>>,
+    channel_receiver: std::sync::mpsc::Receiver<Batch>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    while let Ok(events) = channel_receiver.recv() {
-        let mut insert_event =
-            db.prepare("INSERT INTO event (utc_ms, address, state) VALUES (?1, ?2, ?3)")?;
+    while let Ok(batch) = channel_receiver.recv() {
+        let mut insert_event = db.prepare(
+            "INSERT INTO event
+                (utc_ms, device, unit_id, area, address, previous, value, latency_us)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
         let transaction = db.unchecked_transaction().unwrap();
-        for event in events {
-            let address = format!("{}{}", if event.coil { "%M" } else { "%MW" }, event.address);
-            insert_event.execute((event.utc_ms, address, event.state))?;
+        for event in batch.events {
+            insert_event.execute((
+                event.utc_ms,
+                &batch.device,
+                batch.unit_id,
+                event.table.area(),
+                event.address,
+                event.previous,
+                event.state,
+                event.latency_us,
+            ))?;
         }
         transaction.commit()?;
     }
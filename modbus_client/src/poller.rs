@@ -0,0 +1,276 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+    },
+    time::{Duration, Instant},
+};
+
+use modbus::{Client, Coil, tcp};
+
+use crate::{
+    config::{Area, Config, Target},
+    modbus_utils::{
+        Batch, Event, Table, connection_event, detect_bit_events, detect_register_events,
+        print_coils_and_holding_registers,
+    },
+    tls_transport::TlsTransport,
+    utils::now_utc_ms,
+};
+
+fn connect(
+    target: &Target,
+    config: &Config,
+) -> Result<Box<dyn Client>, Box<dyn std::error::Error>> {
+    let machine_addr = target.address.as_str();
+    let machine_port = target.port;
+    let timeout = Duration::from_millis(config.timeout_ms);
+    println!("Starting transport on {machine_addr}:{machine_port}");
+    let transport: Box<dyn Client> = match &target.tls {
+        Some(files) => {
+            let mut transport = TlsTransport::connect(machine_addr, machine_port, files, timeout)?;
+            transport.set_uid(target.unit_id);
+            Box::new(transport)
+        }
+        None => {
+            let cfg = tcp::Config {
+                tcp_port: machine_port,
+                tcp_connect_timeout: Some(timeout),
+                tcp_read_timeout: Some(timeout),
+                tcp_write_timeout: Some(timeout),
+                modbus_uid: target.unit_id,
+            };
+            Box::new(tcp::Transport::new_with_cfg(machine_addr, cfg)?)
+        }
+    };
+    Ok(transport)
+}
+
+/// Whether the link to the device is broken, rather than the device
+/// refusing the request: a new connection may help.
+fn is_transport_failure(error: &modbus::Error) -> bool {
+    matches!(
+        error,
+        modbus::Error::Io(_) | modbus::Error::InvalidResponse | modbus::Error::InvalidData(_)
+    )
+}
+
+/// Values of all the areas of a device, empty for the areas not polled.
+#[derive(Default)]
+struct Sample {
+    coils: Vec<Coil>,
+    discrete_inputs: Vec<Coil>,
+    input_registers: Vec<u16>,
+    holding_registers: Vec<u16>,
+    /// time taken to read all the areas
+    latency_us: u32,
+}
+
+impl Sample {
+    fn read(transport: &mut dyn Client, target: &Target) -> modbus::Result<Self> {
+        let started = Instant::now();
+        let mut sample = Sample::default();
+        if let Some(area) = target.coils {
+            sample.coils = transport.read_coils(area.start, area.quantity)?;
+        }
+        if let Some(area) = target.discrete_inputs {
+            sample.discrete_inputs = transport.read_discrete_inputs(area.start, area.quantity)?;
+        }
+        if let Some(area) = target.input_registers {
+            sample.input_registers = transport.read_input_registers(area.start, area.quantity)?;
+        }
+        if let Some(area) = target.holding_registers {
+            sample.holding_registers =
+                transport.read_holding_registers(area.start, area.quantity)?;
+        }
+        sample.latency_us = u32::try_from(started.elapsed().as_micros()).unwrap_or(u32::MAX);
+        Ok(sample)
+    }
+
+    fn same_values(&self, other: &Sample) -> bool {
+        self.coils == other.coils
+            && self.discrete_inputs == other.discrete_inputs
+            && self.input_registers == other.input_registers
+            && self.holding_registers == other.holding_registers
+    }
+
+    /// Events turning `self` into `new`; none for an empty (baseline) `self`.
+    fn detect_events(&self, new: &Sample, target: &Target, utc_ms: u64, events: &mut Vec<Event>) {
+        let start_of = |area: Option<Area>| area.map_or(0, |area| area.start);
+        detect_bit_events(
+            events,
+            utc_ms,
+            new.latency_us,
+            Table::Coils,
+            start_of(target.coils),
+            &self.coils,
+            &new.coils,
+        );
+        detect_bit_events(
+            events,
+            utc_ms,
+            new.latency_us,
+            Table::DiscreteInputs,
+            start_of(target.discrete_inputs),
+            &self.discrete_inputs,
+            &new.discrete_inputs,
+        );
+        detect_register_events(
+            events,
+            utc_ms,
+            new.latency_us,
+            Table::InputRegisters,
+            start_of(target.input_registers),
+            &self.input_registers,
+            &new.input_registers,
+        );
+        detect_register_events(
+            events,
+            utc_ms,
+            new.latency_us,
+            Table::HoldingRegisters,
+            start_of(target.holding_registers),
+            &self.holding_registers,
+            &new.holding_registers,
+        );
+    }
+}
+
+/// Polls one device until `must_quit` is set or the run duration is over,
+/// handing its events to the database writer about every second.
+///
+/// A broken connection is retried with an exponential backoff; `connection`
+/// events with state 0 then 1 bound the outage, and the first sample after
+/// it is a new baseline, so what changed meanwhile yields no event. An
+/// exception response only skips the sample.
+pub fn poll_device(
+    target: &Target,
+    config: &Config,
+    must_quit: &AtomicBool,
+    channel_sender: Sender<Batch>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let device = target.device();
+    let mut events = Vec::new();
+    let result = poll(
+        target,
+        config,
+        must_quit,
+        &channel_sender,
+        &device,
+        &mut events,
+    );
+    // keep what was seen before a failure
+    channel_sender.send(Batch {
+        device,
+        unit_id: target.unit_id,
+        events,
+    })?;
+    result
+}
+
+fn poll(
+    target: &Target,
+    config: &Config,
+    must_quit: &AtomicBool,
+    channel_sender: &Sender<Batch>,
+    device: &str,
+    events: &mut Vec<Event>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut transport = None;
+    // start of the current outage, if the device cannot be reached
+    let mut outage_since = None;
+    let mut reconnect_delay = config.reconnect_delay_ms;
+    let mut reconnect_at = 0;
+
+    let mut sample = Sample::default();
+
+    let start = now_utc_ms();
+    let mut last_db_commit = 0;
+
+    while !must_quit.load(Ordering::Relaxed) {
+        if transport.is_none() && now_utc_ms() >= reconnect_at {
+            match connect(target, config) {
+                // the outage lasts until a sample is read: some servers
+                // accept connections only to close them at once
+                Ok(new_transport) => transport = Some(new_transport),
+                Err(e) => {
+                    eprintln!("{device}: cannot connect ({e}), retrying in {reconnect_delay} ms");
+                    if outage_since.is_none() {
+                        let now = now_utc_ms();
+                        outage_since = Some(now);
+                        events.push(connection_event(now, false));
+                    }
+                    reconnect_at = now_utc_ms() + reconnect_delay;
+                    reconnect_delay = (2 * reconnect_delay).min(config.reconnect_max_delay_ms);
+                }
+            }
+        }
+
+        if let Some(client) = transport.as_mut() {
+            match Sample::read(client.as_mut(), target) {
+                Ok(new_sample) => {
+                    reconnect_delay = config.reconnect_delay_ms;
+                    if let Some(since) = outage_since.take() {
+                        let now = now_utc_ms();
+                        println!("{device}: reconnected after {} ms", now - since);
+                        events.push(connection_event(now, true));
+                    }
+
+                    if !sample.holding_registers.is_empty() {
+                        let diff = new_sample.holding_registers[0]
+                            .saturating_sub(sample.holding_registers[0]);
+                        if diff > 1 {
+                            println!("{device}: missed {diff} data packets");
+                            print_coils_and_holding_registers(
+                                &new_sample.coils,
+                                &new_sample.holding_registers,
+                            );
+                        }
+                    }
+
+                    if !sample.same_values(&new_sample) {
+                        //print_coils_and_holding_registers(&new_sample.coils, &new_sample.holding_registers);
+                        sample.detect_events(&new_sample, target, now_utc_ms(), events);
+                        sample = new_sample;
+                    }
+                }
+                Err(e) if is_transport_failure(&e) => {
+                    eprintln!(
+                        "{device}: connection lost ({e:?}), retrying in {reconnect_delay} ms"
+                    );
+                    transport = None;
+                    if outage_since.is_none() {
+                        let now = now_utc_ms();
+                        outage_since = Some(now);
+                        events.push(connection_event(now, false));
+                    }
+                    reconnect_at = now_utc_ms() + reconnect_delay;
+                    reconnect_delay = (2 * reconnect_delay).min(config.reconnect_max_delay_ms);
+                    sample = Sample::default();
+                }
+                // the device answered, maybe busy for a moment: try again at
+                // the next poll
+                Err(e) => eprintln!("{device}: sample skipped ({e:?})"),
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(config.poll_interval_ms));
+
+        if config
+            .duration_ms
+            .is_some_and(|duration| now_utc_ms() - start > duration)
+        {
+            break;
+        }
+
+        if now_utc_ms() - last_db_commit > 1000 {
+            channel_sender.send(Batch {
+                device: device.to_owned(),
+                unit_id: target.unit_id,
+                events: std::mem::take(events),
+            })?;
+            last_db_commit = now_utc_ms();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,256 @@
+use rusqlite::{Connection, Transaction};
+
+/// Layout of the `event` table, stored in `PRAGMA user_version`.
+///
+/// 0. `utc_ms`, `address` as text (`%M3`, `%MW0`...), `state`, and
+///    `device` since several devices are polled
+/// 1. `area` and numeric `address`, `previous` and new `value`, `device`,
+///    `unit_id` and the sample `latency_us`, indexed by time and address
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Migrations from each version to the next one.
+const MIGRATIONS: [fn(&Transaction) -> rusqlite::Result<()>; SCHEMA_VERSION as usize] =
+    [to_version_1];
+
+fn to_version_1(db: &Transaction) -> rusqlite::Result<()> {
+    let old_columns: Vec<String> = db
+        .prepare("SELECT name FROM pragma_table_info('event')")?
+        .query_map((), |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    if !old_columns.is_empty() {
+        db.execute("ALTER TABLE event RENAME TO event_v0", ())?;
+    }
+    db.execute_batch(
+        "CREATE TABLE event (
+            id          INTEGER PRIMARY KEY,
+            utc_ms      INTEGER NOT NULL,
+            device      TEXT,
+            unit_id     INTEGER,
+            area        TEXT NOT NULL,
+            address     INTEGER,
+            previous    INTEGER,
+            value       INTEGER NOT NULL,
+            latency_us  INTEGER );
+        CREATE INDEX event_utc_ms ON event (utc_ms);
+        CREATE INDEX event_address ON event (area, address);",
+    )?;
+    if old_columns.is_empty() {
+        return Ok(());
+    }
+    // an event of a bit always flips it, the previous value of a register
+    // is the one of its last event
+    let device = if old_columns.iter().any(|column| column == "device") {
+        "device"
+    } else {
+        "NULL"
+    };
+    db.execute(
+        &format!(
+            "INSERT INTO event (id, utc_ms, device, area, address, previous, value)
+            SELECT id, utc_ms, {device}, area,
+                CAST(ltrim(address, '%IMW') AS INTEGER),
+                CASE
+                    WHEN area IN ('coil', 'discrete_input', 'connection') THEN 1 - state
+                    ELSE LAG(state) OVER (PARTITION BY {device}, address ORDER BY id)
+                END,
+                state
+            FROM (SELECT *,
+                CASE
+                    WHEN address = 'connection' THEN 'connection'
+                    WHEN substr(address, 1, 3) = '%MW' THEN 'holding_register'
+                    WHEN substr(address, 1, 3) = '%IW' THEN 'input_register'
+                    WHEN substr(address, 1, 2) = '%M' THEN 'coil'
+                    WHEN substr(address, 1, 2) = '%I' THEN 'discrete_input'
+                END AS area
+                FROM event_v0)
+            WHERE area IS NOT NULL"
+        ),
+        (),
+    )?;
+    db.execute(
+        "UPDATE event SET address = NULL WHERE area = 'connection'",
+        (),
+    )?;
+    db.execute("DROP TABLE event_v0", ())?;
+    Ok(())
+}
+
+/// Brings the database to `SCHEMA_VERSION`, each migration in its own
+/// transaction so that an interrupted one is simply run again.
+pub fn migrate(db: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+    let version: u32 = db.query_row("PRAGMA user_version", (), |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        Err(format!(
+            "the database has schema version {version}, this modbus_client only knows \
+             up to {SCHEMA_VERSION}"
+        ))?
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let transaction = db.transaction()?;
+        migration(&transaction)?;
+        transaction.pragma_update(None, "user_version", from as u32 + 1)?;
+        transaction.commit()?;
+        println!("Migrated the database to schema version {}", from + 1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_version(db: &Connection) -> u32 {
+        db.query_row("PRAGMA user_version", (), |row| row.get(0))
+            .unwrap()
+    }
+
+    /// utc_ms, device, unit_id, area, address, previous and value of an event
+    type Row = (
+        u64,
+        Option<String>,
+        Option<u8>,
+        String,
+        Option<u16>,
+        Option<u16>,
+        u16,
+    );
+
+    fn events(db: &Connection) -> Vec<Row> {
+        db.prepare(
+            "SELECT utc_ms, device, unit_id, area, address, previous, value
+            FROM event ORDER BY id",
+        )
+        .unwrap()
+        .query_map((), |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+                row.get(6)?,
+            ))
+        })
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap()
+    }
+
+    fn indexes(db: &Connection) -> Vec<String> {
+        db.prepare("SELECT name FROM pragma_index_list('event') ORDER BY name")
+            .unwrap()
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn from_version_0() {
+        let mut db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE event (
+                id      INTEGER PRIMARY KEY,
+                utc_ms  INTEGER,
+                address TEXT,
+                state   INTEGER );
+            INSERT INTO event (utc_ms, address, state) VALUES
+                (1, '%M3', 1), (2, '%MW0', 5), (3, 'connection', 0),
+                (4, '%MW0', 7), (5, '%IW2', 3), (6, '%I1', 1), (7, '%M3', 0),
+                (8, 'unknown', 1);",
+        )
+        .unwrap();
+        migrate(&mut db).unwrap();
+        assert_eq!(user_version(&db), SCHEMA_VERSION);
+        let event = |utc_ms, area: &str, address, previous, value| {
+            (
+                utc_ms,
+                None,
+                None,
+                area.to_owned(),
+                address,
+                previous,
+                value,
+            )
+        };
+        let expected = vec![
+            event(1, "coil", Some(3), Some(0), 1),
+            event(2, "holding_register", Some(0), None, 5),
+            event(3, "connection", None, Some(1), 0),
+            event(4, "holding_register", Some(0), Some(5), 7),
+            event(5, "input_register", Some(2), None, 3),
+            event(6, "discrete_input", Some(1), Some(0), 1),
+            event(7, "coil", Some(3), Some(1), 0),
+        ];
+        assert_eq!(events(&db), expected);
+        assert_eq!(indexes(&db), ["event_address", "event_utc_ms"]);
+        // nothing left to migrate
+        migrate(&mut db).unwrap();
+        assert_eq!(user_version(&db), SCHEMA_VERSION);
+        assert_eq!(events(&db), expected);
+    }
+
+    #[test]
+    fn from_version_0_with_devices() {
+        let mut db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE event (
+                id      INTEGER PRIMARY KEY,
+                utc_ms  INTEGER,
+                device  TEXT,
+                address TEXT,
+                state   INTEGER );
+            INSERT INTO event (utc_ms, device, address, state) VALUES
+                (1, 'a', '%MW0', 5), (2, 'b', '%MW0', 9), (3, 'a', '%MW0', 6);",
+        )
+        .unwrap();
+        migrate(&mut db).unwrap();
+        let previous: Vec<_> = events(&db)
+            .into_iter()
+            .map(|event| (event.1.unwrap(), event.5))
+            .collect();
+        // the previous value is the one of the same device
+        assert_eq!(
+            previous,
+            [
+                ("a".to_owned(), None),
+                ("b".to_owned(), None),
+                ("a".to_owned(), Some(5))
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_database() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db).unwrap();
+        assert_eq!(user_version(&db), SCHEMA_VERSION);
+        assert!(events(&db).is_empty());
+        assert_eq!(indexes(&db), ["event_address", "event_utc_ms"]);
+    }
+
+    #[test]
+    fn current_version() {
+        let mut db = Connection::open_in_memory().unwrap();
+        migrate(&mut db).unwrap();
+        db.execute(
+            "INSERT INTO event (utc_ms, device, unit_id, area, address, previous, value)
+            VALUES (1, 'plc', 1, 'coil', 3, 0, 1)",
+            (),
+        )
+        .unwrap();
+        let before = events(&db);
+        migrate(&mut db).unwrap();
+        assert_eq!(user_version(&db), SCHEMA_VERSION);
+        assert_eq!(events(&db), before);
+    }
+
+    #[test]
+    fn newer_version() {
+        let mut db = Connection::open_in_memory().unwrap();
+        db.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(migrate(&mut db).is_err());
+    }
+}
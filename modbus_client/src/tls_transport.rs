@@ -0,0 +1,266 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+use modbus::{Client, Coil, Error, ExceptionCode, Reason, Result, binary};
+use rustls::{
+    ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+};
+use serde::Deserialize;
+
+/// Files needed to authenticate against a Modbus/TCP Security server.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsFiles {
+    /// client certificate, whose role extension decides what the server allows
+    pub cert: String,
+    pub key: String,
+    /// authority which issued the server certificate
+    pub ca: String,
+}
+
+/// Modbus/TCP Security (Modbus over TLS) counterpart of `tcp::Transport`.
+///
+/// The `modbus` crate only talks over a plain `TcpStream`, so the MBAP
+/// framing is done here, on top of a rustls session.
+pub struct TlsTransport {
+    tid: u16,
+    uid: u8,
+    stream: StreamOwned<ClientConnection, TcpStream>,
+}
+
+fn client_config(
+    files: &TlsFiles,
+) -> std::result::Result<ClientConfig, Box<dyn std::error::Error>> {
+    let cannot_read = |path: &str, e| format!("cannot read {path:?}: {e}");
+    let certs = CertificateDer::pem_file_iter(&files.cert)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| cannot_read(&files.cert, e))?;
+    let key = PrivateKeyDer::from_pem_file(&files.key).map_err(|e| cannot_read(&files.key, e))?;
+    let mut roots = RootCertStore::empty();
+    for authority in
+        CertificateDer::pem_file_iter(&files.ca).map_err(|e| cannot_read(&files.ca, e))?
+    {
+        roots.add(authority.map_err(|e| cannot_read(&files.ca, e))?)?;
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_client_auth_cert(certs, key)?;
+    Ok(config)
+}
+
+fn exception_code(code: u8) -> Option<ExceptionCode> {
+    Some(match code {
+        0x01 => ExceptionCode::IllegalFunction,
+        0x02 => ExceptionCode::IllegalDataAddress,
+        0x03 => ExceptionCode::IllegalDataValue,
+        0x04 => ExceptionCode::SlaveOrServerFailure,
+        0x05 => ExceptionCode::Acknowledge,
+        0x06 => ExceptionCode::SlaveOrServerBusy,
+        0x07 => ExceptionCode::NegativeAcknowledge,
+        0x08 => ExceptionCode::MemoryParity,
+        0x09 => ExceptionCode::NotDefined,
+        0x0a => ExceptionCode::GatewayPath,
+        0x0b => ExceptionCode::GatewayTarget,
+        _ => return None,
+    })
+}
+
+fn registers(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect()
+}
+
+impl TlsTransport {
+    /// Connects to `addr`, which must match a name of the server certificate.
+    ///
+    /// `timeout` bounds the connection and every later read or write.
+    pub fn connect(
+        addr: &str,
+        port: u16,
+        files: &TlsFiles,
+        timeout: Duration,
+    ) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let config = client_config(files)?;
+        let server_name = ServerName::try_from(addr.to_owned())?;
+        let connection = ClientConnection::new(Arc::new(config), server_name)?;
+        let socket_addr = (addr, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or(format!("cannot resolve {addr:?}"))?;
+        let socket = TcpStream::connect_timeout(&socket_addr, timeout)?;
+        socket.set_nodelay(true)?;
+        socket.set_read_timeout(Some(timeout))?;
+        socket.set_write_timeout(Some(timeout))?;
+        let mut stream = StreamOwned::new(connection, socket);
+        // fail now rather than on the first request if the server refuses us
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+        Ok(TlsTransport {
+            tid: 0,
+            uid: 1,
+            stream,
+        })
+    }
+
+    /// Sends a request PDU and returns the data of the response PDU, after
+    /// its function code.
+    fn exchange(&mut self, pdu: &[u8]) -> Result<Vec<u8>> {
+        self.tid = self.tid.wrapping_add(1);
+        let mut request = Vec::with_capacity(7 + pdu.len());
+        request.extend_from_slice(&self.tid.to_be_bytes());
+        request.extend_from_slice(&[0, 0]);
+        request.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+        request.push(self.uid);
+        request.extend_from_slice(pdu);
+        self.stream.write_all(&request)?;
+
+        let mut header = [0; 7];
+        self.stream.read_exact(&mut header)?;
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if header[..2] != request[..2] || header[2..4] != [0, 0] || length < 2 {
+            return Err(Error::InvalidResponse);
+        }
+        let mut response = vec![0; length - 1];
+        self.stream.read_exact(&mut response)?;
+        if response[0] == pdu[0] | 0x80 {
+            let code = response.get(1).copied().and_then(exception_code);
+            return Err(code.map_or(Error::InvalidResponse, Error::Exception));
+        }
+        if response[0] != pdu[0] {
+            return Err(Error::InvalidResponse);
+        }
+        response.remove(0);
+        Ok(response)
+    }
+
+    fn read(
+        &mut self,
+        function: u8,
+        address: u16,
+        quantity: u16,
+        expected_bytes: usize,
+    ) -> Result<Vec<u8>> {
+        let mut pdu = vec![function];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&quantity.to_be_bytes());
+        let response = self.exchange(&pdu)?;
+        if response.first().map(|count| *count as usize) != Some(expected_bytes)
+            || response.len() != expected_bytes + 1
+        {
+            return Err(Error::InvalidData(Reason::UnexpectedReplySize));
+        }
+        Ok(response[1..].to_vec())
+    }
+
+    fn write_multiple(
+        &mut self,
+        function: u8,
+        address: u16,
+        quantity: u16,
+        bytes: &[u8],
+    ) -> Result<()> {
+        let mut pdu = vec![function];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&quantity.to_be_bytes());
+        pdu.push(bytes.len() as u8);
+        pdu.extend_from_slice(bytes);
+        self.exchange(&pdu)?;
+        Ok(())
+    }
+}
+
+impl Drop for TlsTransport {
+    fn drop(&mut self) {
+        // tell the server the session ends on purpose
+        self.stream.conn.send_close_notify();
+        let _ = self.stream.flush();
+    }
+}
+
+impl Client for TlsTransport {
+    fn read_discrete_inputs(&mut self, address: u16, quantity: u16) -> Result<Vec<Coil>> {
+        let bytes = self.read(0x02, address, quantity, quantity.div_ceil(8) as usize)?;
+        Ok(binary::unpack_bits(&bytes, quantity))
+    }
+
+    fn read_coils(&mut self, address: u16, quantity: u16) -> Result<Vec<Coil>> {
+        let bytes = self.read(0x01, address, quantity, quantity.div_ceil(8) as usize)?;
+        Ok(binary::unpack_bits(&bytes, quantity))
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: Coil) -> Result<()> {
+        let mut pdu = vec![0x05];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        let value: u16 = if value == Coil::On { 0xff00 } else { 0x0000 };
+        pdu.extend_from_slice(&value.to_be_bytes());
+        self.exchange(&pdu)?;
+        Ok(())
+    }
+
+    fn write_multiple_coils(&mut self, address: u16, coils: &[Coil]) -> Result<()> {
+        let bytes = binary::pack_bits(coils);
+        self.write_multiple(0x0f, address, coils.len() as u16, &bytes)
+    }
+
+    fn read_input_registers(&mut self, address: u16, quantity: u16) -> Result<Vec<u16>> {
+        let bytes = self.read(0x04, address, quantity, 2 * quantity as usize)?;
+        Ok(registers(&bytes))
+    }
+
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> Result<Vec<u16>> {
+        let bytes = self.read(0x03, address, quantity, 2 * quantity as usize)?;
+        Ok(registers(&bytes))
+    }
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<()> {
+        let mut pdu = vec![0x06];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&value.to_be_bytes());
+        self.exchange(&pdu)?;
+        Ok(())
+    }
+
+    fn write_multiple_registers(&mut self, address: u16, values: &[u16]) -> Result<()> {
+        let bytes = binary::unpack_bytes(values);
+        self.write_multiple(0x10, address, values.len() as u16, &bytes)
+    }
+
+    fn write_read_multiple_registers(
+        &mut self,
+        write_address: u16,
+        write_quantity: u16,
+        write_values: &[u16],
+        read_address: u16,
+        read_quantity: u16,
+    ) -> Result<Vec<u16>> {
+        let mut pdu = vec![0x17];
+        pdu.extend_from_slice(&read_address.to_be_bytes());
+        pdu.extend_from_slice(&read_quantity.to_be_bytes());
+        pdu.extend_from_slice(&write_address.to_be_bytes());
+        pdu.extend_from_slice(&write_quantity.to_be_bytes());
+        let bytes = binary::unpack_bytes(write_values);
+        pdu.push(bytes.len() as u8);
+        pdu.extend_from_slice(&bytes);
+        let response = self.exchange(&pdu)?;
+        if response.first().map(|count| *count as usize) != Some(2 * read_quantity as usize)
+            || response.len() != 2 * read_quantity as usize + 1
+        {
+            return Err(Error::InvalidData(Reason::UnexpectedReplySize));
+        }
+        Ok(registers(&response[1..]))
+    }
+
+    fn set_uid(&mut self, uid: u8) {
+        self.uid = uid;
+    }
+}
@@ -0,0 +1,162 @@
+//! Compares the threaded TCP server with the tokio one (`--async`).
+//!
+//! Each server is started as a child process, then loaded by client
+//! threads sending "read 10 holding registers" requests back to back for
+//! `BENCH_SECONDS` seconds (2 by default). The report gives the request
+//! rate, the request latencies and the time from connecting to the first
+//! response.
+//!
+//!     cargo bench --bench tcp_throughput
+
+use std::{
+    env,
+    error::Error,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Barrier},
+    thread,
+    time::{Duration, Instant},
+};
+
+const CLIENTS: [usize; 3] = [1, 8, 64];
+/// read 10 holding registers from 0, unit 1
+const REQUEST: [u8; 12] = [0, 0, 0, 0, 0, 6, 1, 3, 0, 0, 0, 10];
+const RESPONSE_LEN: usize = 9 + 20;
+
+struct Server {
+    child: Child,
+    port: u16,
+}
+
+impl Server {
+    fn start(extra_args: &[&str]) -> Result<Self, Box<dyn Error>> {
+        // let the system pick a free port
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let child = Command::new(env!("CARGO_BIN_EXE_modbus_server"))
+            .args(["--port", &port.to_string(), "--holdings", "100"])
+            .args(extra_args)
+            .stdout(Stdio::null())
+            .spawn()?;
+        let server = Server { child, port };
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            if started.elapsed() > Duration::from_secs(5) {
+                Err("the server does not accept connections")?
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        Ok(server)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn exchange(stream: &mut TcpStream, tid: u16) -> std::io::Result<()> {
+    let mut request = REQUEST;
+    request[..2].copy_from_slice(&tid.to_be_bytes());
+    stream.write_all(&request)?;
+    let mut response = [0; RESPONSE_LEN];
+    stream.read_exact(&mut response)?;
+    if response[..2] != request[..2] || response[7] != REQUEST[7] {
+        Err(std::io::Error::other("unexpected response"))?
+    }
+    Ok(())
+}
+
+/// Sends requests on one connection until `duration` is over and returns
+/// the latency of each of them.
+fn load(port: u16, duration: Duration) -> std::io::Result<Vec<Duration>> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+    stream.set_nodelay(true)?;
+    let mut latencies = Vec::new();
+    let started = Instant::now();
+    let mut tid = 0u16;
+    while started.elapsed() < duration {
+        let sent = Instant::now();
+        exchange(&mut stream, tid)?;
+        latencies.push(sent.elapsed());
+        tid = tid.wrapping_add(1);
+    }
+    Ok(latencies)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+fn run_load(
+    port: u16,
+    clients: usize,
+    duration: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let barrier = Arc::new(Barrier::new(clients));
+    let threads: Vec<_> = (0..clients)
+        .map(|_| {
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                load(port, duration)
+            })
+        })
+        .collect();
+    let mut latencies = Vec::new();
+    for thread in threads {
+        latencies.extend(thread.join().map_err(|e| format!("{e:?}"))??);
+    }
+    if latencies.is_empty() {
+        Err("no request completed")?
+    }
+    latencies.sort();
+    println!(
+        "  {:>3} clients: {:>8.0} req/s, p50 {:>8.1?}, p99 {:>8.1?}",
+        clients,
+        latencies.len() as f64 / duration.as_secs_f64(),
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.99),
+    );
+    Ok(())
+}
+
+/// Time from connecting to the first response, which includes waiting for
+/// the server to accept the connection.
+fn first_response(port: u16) -> Result<(), Box<dyn Error>> {
+    let mut times = Vec::new();
+    for _ in 0..20 {
+        let started = Instant::now();
+        let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+        stream.set_nodelay(true)?;
+        exchange(&mut stream, 0)?;
+        times.push(started.elapsed());
+    }
+    times.sort();
+    println!(
+        "  new connection: p50 {:>8.1?}, max {:>8.1?}",
+        percentile(&times, 0.5),
+        times[times.len() - 1],
+    );
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let seconds: f64 = match env::var("BENCH_SECONDS") {
+        Ok(seconds) => seconds.parse()?,
+        Err(_) => 2.0,
+    };
+    let duration = Duration::from_secs_f64(seconds);
+    for (name, args) in [("threaded", &[][..]), ("async", &["--async"][..])] {
+        println!("{name} server");
+        let server = Server::start(args)?;
+        for clients in CLIENTS {
+            run_load(server.port, clients, duration)?;
+        }
+        first_response(server.port)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+#!/bin/sh
+# Generates a local certificate authority and the certificates used by
+# Modbus/TCP Security (TLS with mutual authentication):
+#
+#   ca.pem                      authority trusted by both sides
+#   server.pem, server.key      modbus_server, valid for localhost and
+#                               127.0.0.1 (and $SERVER_NAME if set)
+#   client-rw.pem, client-rw.key  client with the ReadWrite role
+#   client-ro.pem, client-ro.key  client with the ReadOnly role
+#
+# The client role is stored in the Modbus role extension
+# (1.3.6.1.4.1.50316.802.1) as an UTF8String, as defined by the Modbus/TCP
+# Security specification.
+#
+# usage: gen_certs.sh [DIRECTORY]   (default: certs)
+set -eu
+
+dir=${1:-certs}
+days=825
+role_oid=1.3.6.1.4.1.50316.802.1
+mkdir -p "$dir"
+cd "$dir"
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+    -keyout ca.key -out ca.pem -days "$days" \
+    -subj "/CN=modbus simulator CA" \
+    -addext "basicConstraints=critical,CA:TRUE" \
+    -addext "keyUsage=critical,keyCertSign,cRLSign" 2>/dev/null
+
+# sign NAME SUBJECT EXTENSIONS
+sign() {
+    openssl req -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+        -keyout "$1.key" -out "$1.csr" -subj "$2" 2>/dev/null
+    printf '%s\n' "$3" > "$1.ext"
+    openssl x509 -req -in "$1.csr" -CA ca.pem -CAkey ca.key \
+        -CAcreateserial -out "$1.pem" -days "$days" \
+        -extfile "$1.ext" 2>/dev/null
+    rm "$1.csr" "$1.ext"
+    echo "generated $dir/$1.pem"
+}
+
+server_names="DNS:localhost,IP:127.0.0.1"
+if [ -n "${SERVER_NAME:-}" ]; then
+    server_names="$server_names,DNS:$SERVER_NAME"
+fi
+sign server "/CN=modbus_server" "\
+basicConstraints=CA:FALSE
+keyUsage=critical,digitalSignature
+extendedKeyUsage=serverAuth
+subjectAltName=$server_names"
+
+for role in ReadWrite ReadOnly; do
+    case $role in
+        ReadWrite) name=client-rw ;;
+        ReadOnly) name=client-ro ;;
+    esac
+    sign "$name" "/CN=modbus_client $role" "\
+basicConstraints=CA:FALSE
+keyUsage=critical,digitalSignature
+extendedKeyUsage=clientAuth
+$role_oid=ASN1:UTF8String:$role"
+done
+rm -f ca.srl
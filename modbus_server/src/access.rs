@@ -0,0 +1,177 @@
+use std::{error::Error, net::IpAddr, str::FromStr};
+
+use rmodbus::consts::{
+    MODBUS_SET_COIL, MODBUS_SET_COILS_BULK, MODBUS_SET_HOLDING,
+    MODBUS_SET_HOLDINGS_BULK,
+};
+use serde::Deserialize;
+
+use crate::extended::{MASK_WRITE_REGISTER, READ_WRITE_REGISTERS};
+
+/// Address or network of clients, such as `10.0.0.7` or `192.168.1.0/24`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid network {s:?}");
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        Ok(Network { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Network {
+    fn contains(&self, addr: IpAddr) -> bool {
+        // compare IPv4 clients reaching an IPv6 socket as IPv4
+        let addr = addr.to_canonical();
+        let (network, addr, bits) = match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => (
+                u128::from(network.to_bits()),
+                u128::from(addr.to_bits()),
+                32,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                (network.to_bits(), addr.to_bits(), 128)
+            }
+            _ => return false,
+        };
+        let host_bits = bits - u32::from(self.prefix);
+        (network ^ addr).checked_shr(host_bits).unwrap_or(0) == 0
+    }
+}
+
+/// What a client may do, from the most to the least restricted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Restrictions on the clients of the TCP and UDP servers.
+///
+/// When both lists are empty every client may read and write; otherwise
+/// clients must belong to one of them, `read_write` winning over
+/// `read_only`. Serial line clients are never restricted.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    pub read_write: Vec<Network>,
+    pub read_only: Vec<Network>,
+    /// simultaneous TCP connections, unlimited if not set
+    pub max_connections: Option<usize>,
+    /// TCP connections without any request for this long are closed
+    pub idle_timeout_ms: Option<u64>,
+}
+
+impl AccessConfig {
+    /// Permission of a client, `None` if it is not allowed at all.
+    pub fn permission(&self, addr: IpAddr) -> Option<Permission> {
+        let matches = |list: &[Network]| list.iter().any(|n| n.contains(addr));
+        if self.read_write.is_empty() && self.read_only.is_empty()
+            || matches(&self.read_write)
+        {
+            Some(Permission::ReadWrite)
+        } else if matches(&self.read_only) {
+            Some(Permission::ReadOnly)
+        } else {
+            None
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.max_connections == Some(0) {
+            Err("the connection limit must be at least 1")?
+        }
+        if self.idle_timeout_ms == Some(0) {
+            Err("the idle timeout must be at least 1 ms")?
+        }
+        Ok(())
+    }
+}
+
+/// Tells whether a function changes the storage.
+pub fn is_write(func: u8) -> bool {
+    matches!(
+        func,
+        MODBUS_SET_COIL
+            | MODBUS_SET_HOLDING
+            | MODBUS_SET_COILS_BULK
+            | MODBUS_SET_HOLDINGS_BULK
+            | MASK_WRITE_REGISTER
+            | READ_WRITE_REGISTERS
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(network: &str, addr: &str) -> bool {
+        let network: Network = network.parse().unwrap();
+        network.contains(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn whole_address_space() {
+        assert!(contains("0.0.0.0/0", "255.255.255.255"));
+        assert!(contains("10.1.2.3/0", "192.168.0.1"));
+        assert!(contains("::/0", "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"));
+        assert!(contains("2001:db8::/0", "::1"));
+    }
+
+    #[test]
+    fn single_host() {
+        assert!(contains("10.0.0.7/32", "10.0.0.7"));
+        assert!(!contains("10.0.0.7/32", "10.0.0.6"));
+        assert!(contains("10.0.0.7", "10.0.0.7"));
+        assert!(!contains("10.0.0.7", "10.0.0.8"));
+        assert!(contains("2001:db8::7/128", "2001:db8::7"));
+        assert!(!contains("2001:db8::7", "2001:db8::6"));
+        assert!(contains("192.168.1.0/24", "192.168.1.255"));
+        assert!(!contains("192.168.1.0/24", "192.168.2.0"));
+    }
+
+    #[test]
+    fn other_family() {
+        assert!(!contains("0.0.0.0/0", "::1"));
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+        assert!(!contains("::/0", "10.0.0.7"));
+        // except IPv4 clients of an IPv6 socket
+        assert!(contains("10.0.0.0/8", "::ffff:10.0.0.7"));
+        assert!(!contains("10.0.0.0/8", "::ffff:11.0.0.7"));
+    }
+
+    #[test]
+    fn invalid_networks() {
+        for network in ["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0/8", "x"]
+        {
+            assert!(network.parse::<Network>().is_err(), "{network}");
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    net::{SocketAddr, SocketAddrV4},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Link type of packets starting directly with their IP header.
+const LINKTYPE_RAW: u32 = 101;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+struct Writer {
+    file: BufWriter<File>,
+    /// identification field of the next IP packet
+    ip_id: u16,
+}
+
+/// pcap file receiving the Modbus traffic of the TCP and UDP servers.
+///
+/// The simulator only sees the Modbus payloads, so the IPv4, TCP and UDP
+/// headers are synthesized: each TCP connection gets a handshake, sequence
+/// numbers following the exchanged bytes and a closing exchange, so that
+/// Wireshark follows the stream and its Modbus/TCP dissector decodes it
+/// (with "Decode As" when the port is not 502). Serial line traffic is not
+/// captured.
+pub struct Capture {
+    writer: Mutex<Writer>,
+}
+
+/// Internet checksum of the given chunks.
+fn checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in chunks {
+        for pair in chunk.chunks(2) {
+            let high = u32::from(pair[0]) << 8;
+            sum += high | u32::from(pair.get(1).copied().unwrap_or(0));
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn ipv4(addr: SocketAddr) -> Option<SocketAddrV4> {
+    match addr {
+        SocketAddr::V4(addr) => Some(addr),
+        SocketAddr::V6(_) => None,
+    }
+}
+
+impl Capture {
+    pub fn create(path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = File::create(path)
+            .map_err(|e| format!("cannot create {path:?}: {e}"))?;
+        let mut file = BufWriter::new(file);
+        file.write_all(&0xa1b2_c3d4_u32.to_le_bytes())?;
+        file.write_all(&2_u16.to_le_bytes())?; // version 2.4
+        file.write_all(&4_u16.to_le_bytes())?;
+        file.write_all(&0_i32.to_le_bytes())?; // timestamps are UTC
+        file.write_all(&0_u32.to_le_bytes())?;
+        file.write_all(&65535_u32.to_le_bytes())?; // snapshot length
+        file.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        file.flush()?;
+        Ok(Self {
+            writer: Mutex::new(Writer { file, ip_id: 0 }),
+        })
+    }
+
+    /// Appends an IPv4 packet carrying `payload` (a TCP or UDP segment
+    /// whose checksum is still zero).
+    fn write_packet(
+        &self,
+        from: SocketAddrV4,
+        to: SocketAddrV4,
+        proto: u8,
+        mut payload: Vec<u8>,
+    ) {
+        let length = payload.len() as u16;
+        let (src, dst) = (from.ip().octets(), to.ip().octets());
+        let pseudo_header =
+            [&src[..], &dst[..], &[0, proto], &length.to_be_bytes()].concat();
+        let sum = checksum(&[&pseudo_header, &payload]);
+        let offset = if proto == PROTO_TCP { 16 } else { 6 };
+        payload[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+
+        let mut writer = self.writer.lock().unwrap();
+        let mut header = [0; 20];
+        header[0] = 0x45; // IPv4, 20 bytes header
+        header[2..4].copy_from_slice(&(20 + length).to_be_bytes());
+        header[4..6].copy_from_slice(&writer.ip_id.to_be_bytes());
+        header[6] = 0x40; // don't fragment
+        header[8] = 64; // time to live
+        header[9] = proto;
+        header[12..16].copy_from_slice(&src);
+        header[16..20].copy_from_slice(&dst);
+        let sum = checksum(&[&header]);
+        header[10..12].copy_from_slice(&sum.to_be_bytes());
+        writer.ip_id = writer.ip_id.wrapping_add(1);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let size = (header.len() + payload.len()) as u32;
+        let record = [
+            &(now.as_secs() as u32).to_le_bytes()[..],
+            &now.subsec_micros().to_le_bytes(),
+            &size.to_le_bytes(),
+            &size.to_le_bytes(),
+            &header,
+            &payload,
+        ]
+        .concat();
+        let written = writer
+            .file
+            .write_all(&record)
+            .and_then(|()| writer.file.flush());
+        if let Err(e) = written {
+            eprintln!("cannot write the capture: {e}");
+        }
+    }
+
+    /// Starts recording a TCP connection; IPv6 connections are not
+    /// recorded.
+    pub fn tcp_session(
+        &self,
+        client: SocketAddr,
+        server: SocketAddr,
+    ) -> Option<TcpCapture<'_>> {
+        let mut session = TcpCapture {
+            capture: self,
+            client: ipv4(client)?,
+            server: ipv4(server)?,
+            client_seq: 1000,
+            server_seq: 2000,
+            reset: false,
+        };
+        session.segment(true, TCP_SYN, &[]);
+        session.segment(false, TCP_SYN | TCP_ACK, &[]);
+        session.segment(true, TCP_ACK, &[]);
+        Some(session)
+    }
+
+    /// Records one datagram.
+    pub fn udp(&self, from: SocketAddr, to: SocketAddr, payload: &[u8]) {
+        let (Some(from), Some(to)) = (ipv4(from), ipv4(to)) else {
+            return;
+        };
+        let length = (8 + payload.len()) as u16;
+        let datagram = [
+            &from.port().to_be_bytes()[..],
+            &to.port().to_be_bytes(),
+            &length.to_be_bytes(),
+            &[0, 0], // checksum
+            payload,
+        ]
+        .concat();
+        self.write_packet(from, to, PROTO_UDP, datagram);
+    }
+}
+
+/// Recording of one TCP connection, closed when dropped.
+pub struct TcpCapture<'c> {
+    capture: &'c Capture,
+    client: SocketAddrV4,
+    server: SocketAddrV4,
+    /// next sequence number of each side
+    client_seq: u32,
+    server_seq: u32,
+    reset: bool,
+}
+
+impl TcpCapture<'_> {
+    fn segment(&mut self, from_client: bool, flags: u8, payload: &[u8]) {
+        let (from, to, seq, ack) = if from_client {
+            (self.client, self.server, self.client_seq, self.server_seq)
+        } else {
+            (self.server, self.client, self.server_seq, self.client_seq)
+        };
+        // the initial SYN acknowledges nothing
+        let ack = if flags == TCP_SYN { 0 } else { ack };
+        let segment = [
+            &from.port().to_be_bytes()[..],
+            &to.port().to_be_bytes(),
+            &seq.to_be_bytes(),
+            &ack.to_be_bytes(),
+            &[5 << 4, flags],        // 20 bytes header
+            &u16::MAX.to_be_bytes(), // window
+            &[0, 0, 0, 0],           // checksum and urgent pointer
+            payload,
+        ]
+        .concat();
+        // SYN and FIN count as one byte of the sequence
+        let consumed =
+            payload.len() as u32 + u32::from(flags & (TCP_SYN | TCP_FIN) != 0);
+        if from_client {
+            self.client_seq = self.client_seq.wrapping_add(consumed);
+        } else {
+            self.server_seq = self.server_seq.wrapping_add(consumed);
+        }
+        self.capture.write_packet(from, to, PROTO_TCP, segment);
+    }
+
+    pub fn request(&mut self, bytes: &[u8]) {
+        self.segment(true, TCP_PSH | TCP_ACK, bytes);
+    }
+
+    pub fn response(&mut self, bytes: &[u8]) {
+        self.segment(false, TCP_PSH | TCP_ACK, bytes);
+    }
+
+    /// Records the server resetting the connection.
+    pub fn reset(&mut self) {
+        self.segment(false, TCP_RST | TCP_ACK, &[]);
+        self.reset = true;
+    }
+}
+
+impl Drop for TcpCapture<'_> {
+    fn drop(&mut self) {
+        if self.reset {
+            return;
+        }
+        self.segment(true, TCP_FIN | TCP_ACK, &[]);
+        self.segment(false, TCP_FIN | TCP_ACK, &[]);
+        self.segment(true, TCP_ACK, &[]);
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::plc::ScanTime;
+
+#[derive(Default)]
+struct Ticks {
+    /// number of completed PLC scans
+    done: u64,
+    /// number of scans the stepped clock allows so far
+    allowed: u64,
+    /// simulated time of the next scan, in milliseconds
+    elapsed_ms: u64,
+}
+
+/// Counts the PLC scans and, in stepped mode, decides when they may run.
+///
+/// With the wall clock the PLC scans once per period; with the stepped
+/// clock it only scans when `step` is called, so that tests see exact,
+/// reproducible storage states. The operator may pause the program or
+/// change the wall clock period while the simulator runs.
+pub struct PlcClock {
+    stepped: bool,
+    paused: AtomicBool,
+    /// wall clock period, in milliseconds
+    period_ms: AtomicU64,
+    ticks: Mutex<Ticks>,
+    changed: Condvar,
+}
+
+/// How long blocked calls wait before checking `must_quit` again.
+const POLL: Duration = Duration::from_millis(100);
+
+impl PlcClock {
+    pub fn new(stepped: bool, period_ms: u64) -> Self {
+        Self {
+            stepped,
+            paused: AtomicBool::new(false),
+            period_ms: AtomicU64::new(period_ms),
+            ticks: Mutex::new(Ticks::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn is_stepped(&self) -> bool {
+        self.stepped
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn period_ms(&self) -> u64 {
+        self.period_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn set_period_ms(&self, period_ms: u64) {
+        self.period_ms.store(period_ms, Ordering::Relaxed);
+    }
+
+    /// Number of completed scans, which is also the tick of the next one.
+    pub fn tick(&self) -> u64 {
+        self.ticks.lock().unwrap().done
+    }
+
+    /// Time of the next scan, which covers the current period.
+    pub fn scan_time(&self) -> ScanTime {
+        let ticks = self.ticks.lock().unwrap();
+        ScanTime {
+            tick: ticks.done,
+            elapsed_ms: ticks.elapsed_ms,
+            period_ms: self.period_ms(),
+        }
+    }
+
+    /// Allows `count` more scans and waits until they are completed.
+    ///
+    /// Returns the tick reached, or `None` if the server is quitting.
+    pub fn step(&self, count: u64, must_quit: &AtomicBool) -> Option<u64> {
+        let mut ticks = self.ticks.lock().unwrap();
+        ticks.allowed = ticks.allowed.max(ticks.done).saturating_add(count);
+        let goal = ticks.allowed;
+        self.changed.notify_all();
+        while ticks.done < goal {
+            if must_quit.load(Ordering::Relaxed) {
+                return None;
+            }
+            ticks = self.changed.wait_timeout(ticks, POLL).unwrap().0;
+        }
+        Some(ticks.done)
+    }
+
+    /// PLC side of the stepped clock: waits a little for the next scan to
+    /// be allowed and tells whether it is.
+    pub fn wait_step(&self) -> bool {
+        let ticks = self.ticks.lock().unwrap();
+        let (ticks, _) = self
+            .changed
+            .wait_timeout_while(ticks, POLL, |t| t.allowed <= t.done)
+            .unwrap();
+        ticks.allowed > ticks.done
+    }
+
+    /// Records the completion of the scan run at `time`.
+    pub fn scan_done(&self, time: ScanTime) {
+        let mut ticks = self.ticks.lock().unwrap();
+        ticks.done += 1;
+        ticks.elapsed_ms = time.elapsed_ms + time.period_ms;
+        self.changed.notify_all();
+    }
+}
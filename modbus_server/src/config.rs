@@ -0,0 +1,517 @@
+use std::{error::Error, str::FromStr};
+
+use serde::Deserialize;
+
+use crate::{
+    access::{AccessConfig, Network},
+    extended::DeviceIdentity,
+    faults::{parse_codes, FaultConfig},
+    plc::PROGRAMS,
+    signals::SignalConfig,
+    stats::STATS_REGISTERS,
+    storage::Address,
+    tls::TlsConfig,
+};
+
+/// Startup settings of the simulator.
+///
+/// Values come from an optional TOML file (`--config <file>`) and are then
+/// overridden by the remaining command line flags.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub unit_id: u8,
+    pub tcp_port: u16,
+    /// serve TCP clients from a tokio runtime instead of one thread each
+    pub async_tcp: bool,
+    /// time allowed to receive the rest of a TCP frame once it started
+    pub read_timeout_ms: u64,
+    /// UDP port served alongside the TCP one, if any
+    pub udp_port: Option<u16>,
+    pub coils: u16,
+    pub discretes: u16,
+    pub inputs: u16,
+    pub holdings: u16,
+    pub ms_div: u64,
+    /// scan only when asked through the control port instead of every
+    /// `ms_div` milliseconds
+    pub stepped: bool,
+    /// local TCP port accepting control commands, if any
+    pub control_port: Option<u16>,
+    /// read operator commands from the standard input
+    pub console: bool,
+    /// local HTTP port serving the statistics to Prometheus, if any
+    pub stats_port: Option<u16>,
+    /// first input register of the main slave holding the statistics
+    pub stats_registers: Option<u16>,
+    pub program: String,
+    /// signal generators of the main slave
+    pub signals: Vec<SignalConfig>,
+    pub seed: u64,
+    /// serial device on which Modbus RTU is served, if any
+    pub rtu_device: Option<String>,
+    pub baud_rate: u32,
+    pub parity: Parity,
+    /// additional slaves hosted next to the one described above
+    pub slaves: Vec<SlaveConfig>,
+    pub faults: FaultConfig,
+    pub access: AccessConfig,
+    /// Modbus/TCP Security server, served alongside the plain TCP one
+    pub tls: TlsConfig,
+    /// answers to the read device identification function
+    pub identity: DeviceIdentity,
+    /// pcap file receiving the TCP and UDP traffic
+    pub capture: Option<String>,
+    /// file the storage is periodically saved to and restored from
+    pub snapshot: Option<String>,
+    pub snapshot_interval_ms: u64,
+    /// event database recorded by modbus_client, for the replay program
+    pub replay_db: Option<String>,
+    /// device to replay when the database holds several
+    pub replay_device: Option<String>,
+    /// replay time scale, 2.0 replays twice as fast as recorded
+    pub replay_speed: f64,
+}
+
+/// Register map and program of one slave, selected by its unit id.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SlaveConfig {
+    pub unit_id: u8,
+    #[serde(default = "default_coils")]
+    pub coils: u16,
+    #[serde(default)]
+    pub discretes: u16,
+    #[serde(default)]
+    pub inputs: u16,
+    #[serde(default = "default_holdings")]
+    pub holdings: u16,
+    #[serde(default = "default_program")]
+    pub program: String,
+    #[serde(default)]
+    pub signals: Vec<SignalConfig>,
+}
+
+fn default_coils() -> u16 {
+    20
+}
+
+fn default_holdings() -> u16 {
+    5
+}
+
+fn default_program() -> String {
+    "chaser".to_owned()
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+impl FromStr for Parity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Parity::None),
+            "even" => Ok(Parity::Even),
+            "odd" => Ok(Parity::Odd),
+            _ => Err("expected none, even or odd".to_owned()),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            unit_id: 1,
+            tcp_port: 55022,
+            async_tcp: false,
+            read_timeout_ms: 5000,
+            udp_port: None,
+            coils: default_coils(),
+            discretes: 0,
+            inputs: 0,
+            holdings: default_holdings(),
+            ms_div: 100,
+            stepped: false,
+            control_port: None,
+            console: false,
+            stats_port: None,
+            stats_registers: None,
+            program: default_program(),
+            signals: Vec::new(),
+            seed: 0,
+            rtu_device: None,
+            baud_rate: 19200,
+            parity: Parity::Even,
+            slaves: Vec::new(),
+            faults: FaultConfig::default(),
+            access: AccessConfig::default(),
+            tls: TlsConfig::default(),
+            identity: DeviceIdentity::default(),
+            capture: None,
+            snapshot: None,
+            snapshot_interval_ms: 10_000,
+            replay_db: None,
+            replay_device: None,
+            replay_speed: 1.0,
+        }
+    }
+}
+
+const USAGE: &str = "\
+usage: modbus_server [fast] [--config FILE] [--unit-id ID] [--port PORT]
+                     [--async] [--read-timeout MS] [--allow NETWORK]...
+                     [--allow-read-only NETWORK]...
+                     [--max-connections N] [--idle-timeout MS]
+                     [--capture FILE]
+                     [--tls-port PORT] [--tls-cert FILE] [--tls-key FILE]
+                     [--tls-ca FILE]
+                     [--coils N] [--discretes N] [--inputs N] [--holdings N]
+                     [--period MS] [--program NAME] [--seed N]
+                     [--list-programs] [--rtu DEVICE] [--baud RATE]
+                     [--parity none|even|odd] [--udp PORT]
+                     [--slave UNIT_ID[:PROGRAM]]...
+                     [--latency MS] [--jitter MS] [--drop-rate P]
+                     [--exception-rate P] [--exception-codes C,C...]
+                     [--truncate-rate P] [--reset-rate P]
+                     [--fault-schedule FILE] [--snapshot FILE]
+                     [--snapshot-interval MS] [--replay FILE]
+                     [--replay-speed FACTOR] [--replay-device NAME]
+                     [--signal TARGET=KIND[:key=value,...]]...
+                     [--stepped] [--control PORT] [--console]
+                     [--stats-port PORT] [--stats-registers ADDRESS]
+                     [--vendor NAME] [--product-code CODE]
+                     [--revision REV] [--product-name NAME]
+                     [--model-name NAME]";
+
+fn parse_value<T>(
+    flag: &str,
+    value: Option<String>,
+) -> Result<T, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = value.ok_or_else(|| format!("missing value for {flag}"))?;
+    value
+        .parse()
+        .map_err(|e| format!("invalid value {value:?} for {flag}: {e}").into())
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {path:?}: {e}"))?;
+        Ok(toml::from_str(&text)
+            .map_err(|e| format!("invalid config {path:?}: {e}"))?)
+    }
+
+    pub fn from_args(
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let args: Vec<String> = args.into_iter().collect();
+        let mut config = match args.iter().position(|a| a == "--config") {
+            Some(i) => Config::load(
+                args.get(i + 1).ok_or("missing value for --config")?,
+            )?,
+            None => Config::default(),
+        };
+        // built once every flag is known, --coils and the others apply
+        // wherever they are placed
+        let mut cli_slaves = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "fast" => config.ms_div = 1,
+                "--config" => {
+                    args.next();
+                }
+                "--unit-id" => {
+                    config.unit_id = parse_value(&arg, args.next())?
+                }
+                "--port" => config.tcp_port = parse_value(&arg, args.next())?,
+                "--coils" => config.coils = parse_value(&arg, args.next())?,
+                "--discretes" => {
+                    config.discretes = parse_value(&arg, args.next())?
+                }
+                "--inputs" => config.inputs = parse_value(&arg, args.next())?,
+                "--holdings" => {
+                    config.holdings = parse_value(&arg, args.next())?
+                }
+                "--period" => config.ms_div = parse_value(&arg, args.next())?,
+                "--stepped" => config.stepped = true,
+                "--control" => {
+                    config.control_port = Some(parse_value(&arg, args.next())?)
+                }
+                "--console" => config.console = true,
+                "--stats-port" => {
+                    config.stats_port = Some(parse_value(&arg, args.next())?)
+                }
+                "--stats-registers" => {
+                    config.stats_registers =
+                        Some(parse_value(&arg, args.next())?)
+                }
+                "--program" => {
+                    config.program = parse_value(&arg, args.next())?
+                }
+                "--signal" => {
+                    config.signals.push(parse_value(&arg, args.next())?)
+                }
+                "--seed" => config.seed = parse_value(&arg, args.next())?,
+                "--slave" => {
+                    let value: String = parse_value(&arg, args.next())?;
+                    let (unit_id, program) = match value.split_once(':') {
+                        Some((unit_id, program)) => {
+                            (unit_id, Some(program.to_owned()))
+                        }
+                        None => (value.as_str(), None),
+                    };
+                    let unit_id: u8 =
+                        parse_value(&arg, Some(unit_id.to_owned()))?;
+                    cli_slaves.push((unit_id, program));
+                }
+                "--async" => config.async_tcp = true,
+                "--read-timeout" => {
+                    config.read_timeout_ms = parse_value(&arg, args.next())?
+                }
+                "--allow" => {
+                    let network: Network = parse_value(&arg, args.next())?;
+                    config.access.read_write.push(network)
+                }
+                "--allow-read-only" => {
+                    let network: Network = parse_value(&arg, args.next())?;
+                    config.access.read_only.push(network)
+                }
+                "--max-connections" => {
+                    config.access.max_connections =
+                        Some(parse_value(&arg, args.next())?)
+                }
+                "--idle-timeout" => {
+                    config.access.idle_timeout_ms =
+                        Some(parse_value(&arg, args.next())?)
+                }
+                "--tls-port" => {
+                    config.tls.port = Some(parse_value(&arg, args.next())?)
+                }
+                "--tls-cert" => {
+                    config.tls.cert = Some(parse_value(&arg, args.next())?)
+                }
+                "--tls-key" => {
+                    config.tls.key = Some(parse_value(&arg, args.next())?)
+                }
+                "--tls-ca" => {
+                    config.tls.ca = Some(parse_value(&arg, args.next())?)
+                }
+                "--udp" => {
+                    config.udp_port = Some(parse_value(&arg, args.next())?)
+                }
+                "--rtu" => {
+                    config.rtu_device = Some(parse_value(&arg, args.next())?)
+                }
+                "--baud" => config.baud_rate = parse_value(&arg, args.next())?,
+                "--parity" => config.parity = parse_value(&arg, args.next())?,
+                "--latency" => {
+                    config.faults.latency_ms = parse_value(&arg, args.next())?
+                }
+                "--jitter" => {
+                    config.faults.jitter_ms = parse_value(&arg, args.next())?
+                }
+                "--drop-rate" => {
+                    config.faults.drop_rate = parse_value(&arg, args.next())?
+                }
+                "--exception-rate" => {
+                    config.faults.exception_rate =
+                        parse_value(&arg, args.next())?
+                }
+                "--exception-codes" => {
+                    let value: String = parse_value(&arg, args.next())?;
+                    config.faults.exception_codes = parse_codes(&value)?
+                }
+                "--truncate-rate" => {
+                    config.faults.truncate_rate =
+                        parse_value(&arg, args.next())?
+                }
+                "--reset-rate" => {
+                    config.faults.reset_rate = parse_value(&arg, args.next())?
+                }
+                "--fault-schedule" => {
+                    config.faults.schedule =
+                        Some(parse_value(&arg, args.next())?)
+                }
+                "--vendor" => {
+                    config.identity.vendor_name =
+                        parse_value(&arg, args.next())?
+                }
+                "--product-code" => {
+                    config.identity.product_code =
+                        parse_value(&arg, args.next())?
+                }
+                "--revision" => {
+                    config.identity.revision = parse_value(&arg, args.next())?
+                }
+                "--product-name" => {
+                    config.identity.product_name =
+                        Some(parse_value(&arg, args.next())?)
+                }
+                "--model-name" => {
+                    config.identity.model_name =
+                        Some(parse_value(&arg, args.next())?)
+                }
+                "--capture" => {
+                    config.capture = Some(parse_value(&arg, args.next())?)
+                }
+                "--snapshot" => {
+                    config.snapshot = Some(parse_value(&arg, args.next())?)
+                }
+                "--snapshot-interval" => {
+                    config.snapshot_interval_ms =
+                        parse_value(&arg, args.next())?
+                }
+                "--replay" => {
+                    config.replay_db = Some(parse_value(&arg, args.next())?)
+                }
+                "--replay-device" => {
+                    config.replay_device =
+                        Some(parse_value(&arg, args.next())?)
+                }
+                "--replay-speed" => {
+                    config.replay_speed = parse_value(&arg, args.next())?
+                }
+                "--list-programs" => {
+                    for program in PROGRAMS {
+                        println!(
+                            "{:<16}{}",
+                            program.name, program.description
+                        );
+                    }
+                    std::process::exit(0);
+                }
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                _ => Err(format!("unexpected argument {arg:?}\n{USAGE}"))?,
+            }
+        }
+        for (unit_id, program) in cli_slaves {
+            config.slaves.push(SlaveConfig {
+                unit_id,
+                coils: config.coils,
+                discretes: config.discretes,
+                inputs: config.inputs,
+                holdings: config.holdings,
+                program: program.unwrap_or_else(|| config.program.clone()),
+                signals: Vec::new(),
+            });
+        }
+        if config.ms_div == 0 {
+            Err("the PLC period must be at least 1 ms")?
+        }
+        if config.baud_rate == 0 {
+            Err("the baud rate must be at least 1")?
+        }
+        if config.read_timeout_ms == 0 {
+            Err("the read timeout must be at least 1 ms")?
+        }
+        if config.stepped && config.control_port.is_none() {
+            Err("the stepped clock needs a control port (--control PORT)")?
+        }
+        if config.replay_speed.is_nan() || config.replay_speed <= 0.0 {
+            Err("the replay speed must be positive")?
+        }
+        if config.snapshot_interval_ms == 0 {
+            Err("the snapshot interval must be at least 1 ms")?
+        }
+        if let Some(start) = config.stats_registers {
+            let end = u32::from(start) + u32::from(STATS_REGISTERS);
+            if end > u32::from(config.inputs) {
+                Err(format!(
+                    "the statistics need the input registers {start} to {} \
+                     (--inputs {end})",
+                    end - 1
+                ))?
+            }
+            let block = u32::from(start)..end;
+            let program = PROGRAMS.iter().find(|p| p.name == config.program);
+            let program_inputs = program
+                .into_iter()
+                .flat_map(|p| p.inputs)
+                .map(|input| (*input, "the PLC program"));
+            let signal_inputs = config.signals.iter().filter_map(|signal| {
+                match signal.target.parse() {
+                    Ok(Address::Input(input)) => Some((input, "a signal")),
+                    _ => None,
+                }
+            });
+            for (input, user) in program_inputs.chain(signal_inputs) {
+                if block.contains(&u32::from(input)) {
+                    Err(format!(
+                        "the statistics block overlaps %IW{input}, written \
+                         by {user}"
+                    ))?
+                }
+            }
+        }
+        let mut unit_ids: Vec<_> =
+            config.all_slaves().iter().map(|s| s.unit_id).collect();
+        if unit_ids.iter().any(|u| *u == 0 || *u == 255) {
+            Err("unit ids 0 and 255 are reserved for broadcast")?
+        }
+        unit_ids.sort();
+        if unit_ids.windows(2).any(|w| w[0] == w[1]) {
+            Err("several slaves share the same unit id")?
+        }
+        config.faults.validate()?;
+        config.identity.validate()?;
+        config.access.validate()?;
+        config.tls.validate()?;
+        Ok(config)
+    }
+
+    /// The main slave followed by the additional ones.
+    pub fn all_slaves(&self) -> Vec<SlaveConfig> {
+        let main = SlaveConfig {
+            unit_id: self.unit_id,
+            coils: self.coils,
+            discretes: self.discretes,
+            inputs: self.inputs,
+            holdings: self.holdings,
+            program: self.program.clone(),
+            signals: self.signals.clone(),
+        };
+        std::iter::once(main)
+            .chain(self.slaves.iter().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_args(args: &str) -> Result<Config, Box<dyn Error>> {
+        Config::from_args(args.split_whitespace().map(str::to_owned))
+    }
+
+    #[test]
+    fn stats_block_overlap() {
+        let tank = "--program tank --inputs 50 --discretes 2";
+        assert!(from_args(&format!("{tank} --stats-registers 0")).is_err());
+        assert!(from_args(&format!("{tank} --stats-registers 1")).is_ok());
+        let signal = "--inputs 50 --signal %IW30=sine";
+        assert!(from_args(&format!("{signal} --stats-registers 0")).is_err());
+        assert!(from_args(&format!("{signal} --stats-registers 1")).is_err());
+        assert!(from_args(&format!(
+            "{signal} --stats-registers 0 --inputs 80"
+        ))
+        .is_err());
+        let signal = "--inputs 80 --signal %IW30=sine";
+        assert!(from_args(&format!("{signal} --stats-registers 31")).is_ok());
+    }
+}
@@ -0,0 +1,332 @@
+use std::{
+    error::Error,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::{atomic::Ordering, Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    storage::{Address, ModbusSimu},
+    SharedState,
+};
+
+const HELP: &str = "\
+commands:
+  show [UNIT]                 show the storage and the forced values
+  force ADDRESS VALUE [UNIT]  force a location, e.g. force %M3 1
+  unforce ADDRESS [UNIT]      release a forced location
+  unforce all [UNIT]          release every forced location
+  pause                       stop running the PLC program
+  resume                      run the PLC program again
+  rate [MS]                   show or change the PLC period
+  tick                        show the number of completed PLC scans
+  step [N]                    run N PLC scans (default 1, at most
+                              1000000), stepped clock only
+  help                        show this help
+
+ADDRESS is %M<n> (coil), %I<n> (discrete input), %IW<n> (input register)
+or %MW<n> (holding register); UNIT defaults to the first slave.";
+
+/// Most scans a single `step` command runs, so that a client cannot hold
+/// the control port for ever.
+const MAX_STEPS: u64 = 1_000_000;
+
+/// Looks up the slave named by an optional unit id argument.
+fn slave<'s>(
+    unit_id: Option<&str>,
+    state: &'s SharedState,
+) -> Result<(u8, &'s RwLock<ModbusSimu>), String> {
+    let unit_id = match unit_id {
+        Some(unit_id) => unit_id
+            .parse()
+            .map_err(|_| format!("invalid unit id {unit_id:?}"))?,
+        None => state.main_unit_id,
+    };
+    match state.slaves.get(&unit_id) {
+        Some(context) => Ok((unit_id, context)),
+        None => Err(format!("no slave with unit id {unit_id}")),
+    }
+}
+
+fn show(
+    unit_id: u8,
+    context: &RwLock<ModbusSimu>,
+    state: &SharedState,
+) -> String {
+    let bits = |area: &[bool]| {
+        area.iter()
+            .map(|b| if *b { '1' } else { '0' })
+            .collect::<String>()
+    };
+    let words = |area: &[u16]| {
+        area.iter()
+            .map(u16::to_string)
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let status = if state.clock.is_paused() {
+        "paused"
+    } else {
+        "running"
+    };
+    let mut text = format!(
+        "unit {unit_id}, tick {}, {status}, period {} ms",
+        state.clock.tick(),
+        state.clock.period_ms()
+    );
+    {
+        let storage = context.read().unwrap();
+        let _ = write!(text, "\ncoils      %M0..  {}", bits(&storage.coils));
+        let _ =
+            write!(text, "\ndiscretes  %I0..  {}", bits(&storage.discretes));
+        let _ = write!(text, "\ninputs     %IW0.. {}", words(&storage.inputs));
+        let _ =
+            write!(text, "\nholdings   %MW0.. {}", words(&storage.holdings));
+    }
+    let forced = state.forces.list(unit_id);
+    if !forced.is_empty() {
+        text.push_str("\nforced    ");
+        for (address, value) in forced {
+            let _ = write!(text, " {address}={value}");
+        }
+    }
+    text
+}
+
+/// Executes one control command and returns the text to answer.
+fn execute(line: &str, state: &SharedState) -> Result<String, String> {
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else {
+        return Ok(String::new());
+    };
+    match command {
+        "show" => {
+            let (unit_id, context) = slave(words.next(), state)?;
+            Ok(show(unit_id, context, state))
+        }
+        "force" => {
+            let (Some(address), Some(value)) = (words.next(), words.next())
+            else {
+                Err("usage: force ADDRESS VALUE [UNIT]")?
+            };
+            let address: Address = address.parse()?;
+            let value = value
+                .parse()
+                .map_err(|_| format!("invalid value {value:?}"))?;
+            let (unit_id, context) = slave(words.next(), state)?;
+            let mut storage = context.write().unwrap();
+            storage
+                .set(address, value)
+                .map_err(|_| format!("{address} is out of the storage"))?;
+            state.forces.set(unit_id, address, value);
+            Ok(format!("{address} forced to {value}"))
+        }
+        "unforce" => {
+            let Some(address) = words.next() else {
+                Err("usage: unforce ADDRESS|all [UNIT]")?
+            };
+            let (unit_id, _) = slave(words.next(), state)?;
+            if address == "all" {
+                let count = state.forces.clear(unit_id);
+                return Ok(format!("{count} location(s) released"));
+            }
+            let address: Address = address.parse()?;
+            if state.forces.remove(unit_id, address) {
+                Ok(format!("{address} released"))
+            } else {
+                Err(format!("{address} is not forced"))
+            }
+        }
+        "pause" => {
+            state.clock.set_paused(true);
+            Ok("PLC program paused".to_owned())
+        }
+        "resume" => {
+            state.clock.set_paused(false);
+            Ok("PLC program running".to_owned())
+        }
+        "rate" => {
+            if let Some(period) = words.next() {
+                let period = period
+                    .parse()
+                    .ok()
+                    .filter(|p| *p > 0)
+                    .ok_or_else(|| format!("invalid period {period:?}"))?;
+                state.clock.set_period_ms(period);
+            }
+            Ok(format!("period {} ms", state.clock.period_ms()))
+        }
+        "tick" => Ok(format!("tick {}", state.clock.tick())),
+        "step" => {
+            if !state.clock.is_stepped() {
+                Err("the PLC runs on the wall clock (start with --stepped)")?
+            }
+            if state.clock.is_paused() {
+                Err("the PLC program is paused")?
+            }
+            let count = match words.next() {
+                Some(count) => count
+                    .parse()
+                    .ok()
+                    .filter(|count| *count <= MAX_STEPS)
+                    .ok_or_else(|| format!("invalid step count {count:?}"))?,
+                None => 1,
+            };
+            match state.clock.step(count, &state.must_quit) {
+                Some(tick) => Ok(format!("tick {tick}")),
+                None => Err("the server is quitting".to_owned()),
+            }
+        }
+        "help" => Ok(HELP.to_owned()),
+        _ => Err(format!("unknown command {command:?}, try help")),
+    }
+}
+
+fn control_dialogue(
+    stream: TcpStream,
+    state: &SharedState,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let answer = match execute(&line?, state) {
+            Ok(answer) => answer,
+            Err(e) => format!("error: {e}"),
+        };
+        if !answer.is_empty() {
+            writeln!(writer, "{answer}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads control commands from the standard input until it is closed.
+pub fn console(state: Arc<SharedState>) -> Result<(), Box<dyn Error>> {
+    println!("operator console ready, type help for the commands");
+    let mut line = String::new();
+    while !state.must_quit.load(Ordering::Relaxed) {
+        print!("> ");
+        io::stdout().flush()?;
+        line.clear();
+        if io::stdin().read_line(&mut line)? == 0 {
+            break; // EOF
+        }
+        match execute(&line, &state) {
+            Ok(answer) if answer.is_empty() => {}
+            Ok(answer) => println!("{answer}"),
+            Err(e) => println!("error: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// Serves line-based control commands on a local TCP port.
+pub fn control_server(
+    control_port: u16,
+    state: Arc<SharedState>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, control_port))?;
+    listener.set_nonblocking(true)?;
+    println!("control commands accepted on 127.0.0.1:{}", control_port);
+    while !state.must_quit.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                thread::spawn({
+                    let state = Arc::clone(&state);
+                    move || {
+                        if let Err(e) = control_dialogue(stream, &state) {
+                            eprintln!("{:?}", e);
+                        }
+                    }
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(200));
+            }
+            Err(e) => Err(e)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, sync::atomic::AtomicBool};
+
+    use super::*;
+    use crate::{
+        clock::PlcClock,
+        config::Config,
+        plc::{program_by_name, PlcProgram},
+        run_plc,
+        tests::shared_state,
+    };
+
+    /// Runs the chaser on slave 1 with the stepped clock until `test`
+    /// returns, and checks that the PLC then stops.
+    fn with_stepped_chaser(test: impl FnOnce(&SharedState)) {
+        let state = shared_state(
+            vec![(1, ModbusSimu::new(0, 0, 0, 4))],
+            PlcClock::new(true, 100),
+        );
+        thread::scope(|scope| {
+            let plc = scope.spawn(|| {
+                let mut programs: Vec<(u8, Box<dyn PlcProgram>)> = vec![(
+                    1,
+                    program_by_name("chaser", &Config::default()).unwrap(),
+                )];
+                run_plc(&state, &mut programs, &BTreeSet::new()).unwrap();
+            });
+            test(&state);
+            state.must_quit.store(true, Ordering::Relaxed);
+            plc.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn step_plc() {
+        with_stepped_chaser(|state| {
+            assert_eq!(state.clock.step(7, &state.must_quit), Some(7));
+            assert_eq!(state.clock.tick(), 7);
+            // the last scan had tick 6, holding i counts every i + 1 ticks
+            let holdings = state.slaves[&1].read().unwrap().holdings.to_vec();
+            assert_eq!(holdings, [6, 3, 2, 1]);
+            assert_eq!(execute("step 3", state).unwrap(), "tick 10");
+            assert_eq!(state.clock.tick(), 10);
+        });
+    }
+
+    #[test]
+    fn rate_sets_simulated_time() {
+        with_stepped_chaser(|state| {
+            state.clock.step(2, &state.must_quit);
+            assert_eq!(execute("rate 1000", state).unwrap(), "period 1000 ms");
+            state.clock.step(1, &state.must_quit);
+            let time = state.clock.scan_time();
+            assert_eq!((time.tick, time.elapsed_ms), (3, 1200));
+            assert_eq!(time.period_ms, 1000);
+        });
+    }
+
+    #[test]
+    fn step_refused() {
+        with_stepped_chaser(|state| {
+            for count in ["18446744073709551615", "1000001", "-1", "x"] {
+                assert!(execute(&format!("step {count}"), state).is_err());
+            }
+            state.clock.set_paused(true);
+            assert!(execute("step", state).is_err());
+            state.clock.set_paused(false);
+            assert_eq!(state.clock.tick(), 0);
+        });
+        // a quitting server does not wait for the scans
+        let clock = PlcClock::new(true, 100);
+        let must_quit = AtomicBool::new(true);
+        assert_eq!(clock.step(1, &must_quit), None);
+        // nor overflows its goal
+        assert_eq!(clock.step(u64::MAX, &must_quit), None);
+    }
+}
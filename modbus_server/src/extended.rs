@@ -0,0 +1,471 @@
+use std::error::Error;
+
+use rmodbus::{
+    consts::{
+        MODBUS_ERROR_ILLEGAL_DATA_ADDRESS, MODBUS_ERROR_ILLEGAL_DATA_VALUE,
+        MODBUS_ERROR_ILLEGAL_FUNCTION,
+    },
+    ModbusProto,
+};
+use serde::Deserialize;
+
+use crate::{
+    frame::{exception_response, frame_response, split_frame},
+    SharedState,
+};
+
+pub const MASK_WRITE_REGISTER: u8 = 0x16;
+pub const READ_WRITE_REGISTERS: u8 = 0x17;
+pub const ENCAPSULATED_INTERFACE: u8 = 0x2b;
+/// MEI type of the read device identification function
+const READ_DEVICE_ID: u8 = 0x0e;
+
+/// Largest PDU allowed by the Modbus specification.
+const MAX_PDU_LEN: usize = 253;
+
+/// Strings returned by the read device identification function (43/14).
+///
+/// The first three objects form the basic identification and are always
+/// returned; the others are part of the regular identification and are
+/// only returned when configured.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceIdentity {
+    pub vendor_name: String,
+    pub product_code: String,
+    pub revision: String,
+    pub vendor_url: Option<String>,
+    pub product_name: Option<String>,
+    pub model_name: Option<String>,
+    pub user_application_name: Option<String>,
+}
+
+impl Default for DeviceIdentity {
+    fn default() -> Self {
+        Self {
+            vendor_name: "modbus_server".to_owned(),
+            product_code: "SIMU".to_owned(),
+            revision: env!("CARGO_PKG_VERSION").to_owned(),
+            vendor_url: None,
+            product_name: None,
+            model_name: None,
+            user_application_name: None,
+        }
+    }
+}
+
+impl DeviceIdentity {
+    /// Configured objects, by increasing object id.
+    fn objects(&self) -> Vec<(u8, &str)> {
+        let basic = [&self.vendor_name, &self.product_code, &self.revision];
+        let regular = [
+            &self.vendor_url,
+            &self.product_name,
+            &self.model_name,
+            &self.user_application_name,
+        ];
+        let basic = basic.into_iter().map(|s| Some(s.as_str()));
+        let regular = regular.into_iter().map(|s| s.as_deref());
+        (0..)
+            .zip(basic.chain(regular))
+            .filter_map(|(id, value)| Some((id, value?)))
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        // an object and the response header must fit in a single PDU
+        if self.objects().iter().any(|(_, value)| value.len() > 240) {
+            Err("device identification strings are limited to 240 bytes")?
+        }
+        Ok(())
+    }
+}
+
+/// Tells whether a function code is processed here rather than by rmodbus,
+/// which does not support it.
+pub fn is_extended(func: u8) -> bool {
+    matches!(
+        func,
+        MASK_WRITE_REGISTER | READ_WRITE_REGISTERS | ENCAPSULATED_INTERFACE
+    )
+}
+
+fn read_u16(pdu: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([pdu[offset], pdu[offset + 1]])
+}
+
+/// Function 22: `holding = (holding & and_mask) | (or_mask & !and_mask)`.
+fn mask_write_register(
+    state: &SharedState,
+    unit_id: u8,
+    pdu: &[u8],
+) -> Result<Vec<u8>, u8> {
+    if pdu.len() != 7 {
+        return Err(MODBUS_ERROR_ILLEGAL_DATA_VALUE);
+    }
+    let reg = read_u16(pdu, 1) as usize;
+    let (and_mask, or_mask) = (read_u16(pdu, 3), read_u16(pdu, 5));
+    let mut storage = state.slaves[&unit_id].write().unwrap();
+    let holding = storage
+        .holdings
+        .get_mut(reg)
+        .ok_or(MODBUS_ERROR_ILLEGAL_DATA_ADDRESS)?;
+    *holding = (*holding & and_mask) | (or_mask & !and_mask);
+    // forced values win over client writes
+    state.forces.apply(unit_id, &mut storage);
+    // the response echoes the request
+    Ok(pdu.to_vec())
+}
+
+/// Function 23: writes holding registers, then reads holding registers, as
+/// a single operation.
+fn read_write_registers(
+    state: &SharedState,
+    unit_id: u8,
+    pdu: &[u8],
+) -> Result<Vec<u8>, u8> {
+    if pdu.len() < 10 {
+        return Err(MODBUS_ERROR_ILLEGAL_DATA_VALUE);
+    }
+    let read_reg = read_u16(pdu, 1) as usize;
+    let read_count = read_u16(pdu, 3) as usize;
+    let write_reg = read_u16(pdu, 5) as usize;
+    let write_count = read_u16(pdu, 7) as usize;
+    let byte_count = pdu[9] as usize;
+    if !(1..=125).contains(&read_count)
+        || !(1..=121).contains(&write_count)
+        || byte_count != write_count * 2
+        || pdu.len() != 10 + byte_count
+    {
+        return Err(MODBUS_ERROR_ILLEGAL_DATA_VALUE);
+    }
+    let mut storage = state.slaves[&unit_id].write().unwrap();
+    let size = storage.holdings.len();
+    if read_reg + read_count > size || write_reg + write_count > size {
+        return Err(MODBUS_ERROR_ILLEGAL_DATA_ADDRESS);
+    }
+    for i in 0..write_count {
+        storage.holdings[write_reg + i] = read_u16(pdu, 10 + i * 2);
+    }
+    state.forces.apply(unit_id, &mut storage);
+    let mut response = vec![READ_WRITE_REGISTERS, (read_count * 2) as u8];
+    for value in &storage.holdings[read_reg..read_reg + read_count] {
+        response.extend_from_slice(&value.to_be_bytes());
+    }
+    Ok(response)
+}
+
+/// Function 43/14: read device identification.
+///
+/// Stream accesses (codes 1 to 3) return the objects of the requested
+/// category from the given object id on, continuing with `more follows`
+/// when they do not fit in one response; individual access (code 4)
+/// returns a single object. There are no extended objects, so code 3
+/// returns the same objects as code 2.
+fn read_device_identification(
+    state: &SharedState,
+    pdu: &[u8],
+) -> Result<Vec<u8>, u8> {
+    if pdu.len() != 4 {
+        return Err(MODBUS_ERROR_ILLEGAL_DATA_VALUE);
+    }
+    if pdu[1] != READ_DEVICE_ID {
+        return Err(MODBUS_ERROR_ILLEGAL_FUNCTION);
+    }
+    let (code, object_id) = (pdu[2], pdu[3]);
+    let objects = state.identity.objects();
+    let selected: Vec<_> = match code {
+        1..=3 => {
+            let last = if code == 1 { 2 } else { u8::MAX };
+            let category: Vec<_> =
+                objects.into_iter().filter(|(id, _)| *id <= last).collect();
+            // an unknown object id restarts the stream at the beginning
+            let start = category
+                .iter()
+                .position(|(id, _)| *id == object_id)
+                .unwrap_or(0);
+            category[start..].to_vec()
+        }
+        4 => {
+            let object = objects.into_iter().find(|(id, _)| *id == object_id);
+            vec![object.ok_or(MODBUS_ERROR_ILLEGAL_DATA_ADDRESS)?]
+        }
+        _ => return Err(MODBUS_ERROR_ILLEGAL_DATA_VALUE),
+    };
+    // regular identification (stream and individual access) when any
+    // regular object is configured, basic identification otherwise
+    let conformity = if state.identity.objects().len() > 3 {
+        0x82
+    } else {
+        0x81
+    };
+    let mut response = vec![
+        ENCAPSULATED_INTERFACE,
+        READ_DEVICE_ID,
+        code,
+        conformity,
+        0,
+        0,
+        0,
+    ];
+    let mut count = 0;
+    for (id, value) in &selected {
+        if response.len() + 2 + value.len() > MAX_PDU_LEN {
+            // more follows, from this object on
+            response[4] = 0xff;
+            response[5] = *id;
+            break;
+        }
+        response.extend_from_slice(&[*id, value.len() as u8]);
+        response.extend_from_slice(value.as_bytes());
+        count += 1;
+    }
+    response[6] = count;
+    Ok(response)
+}
+
+/// Processes a request whose function code is not supported by rmodbus
+/// for the slave `unit_id`, with the same semantics as
+/// `process_slave_request`.
+pub fn process_extended_request(
+    state: &SharedState,
+    unit_id: u8,
+    bytes: &[u8],
+    proto: ModbusProto,
+    exception: Option<u8>,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let (unit, pdu) = split_frame(bytes, proto)?;
+    let func = pdu[0];
+    let broadcast = unit == 0 || unit == 255;
+    if broadcast {
+        // only writes make sense without a response
+        if func == MASK_WRITE_REGISTER {
+            let _ = mask_write_register(state, unit_id, pdu);
+        }
+        return Ok(None);
+    }
+    let result = match (exception, func) {
+        (Some(code), _) => Err(code),
+        (None, MASK_WRITE_REGISTER) => {
+            mask_write_register(state, unit_id, pdu)
+        }
+        (None, READ_WRITE_REGISTERS) => {
+            read_write_registers(state, unit_id, pdu)
+        }
+        (None, _) => read_device_identification(state, pdu),
+    };
+    Ok(Some(match result {
+        Ok(pdu) => frame_response(bytes, unit_id, &pdu, proto),
+        Err(code) => exception_response(bytes, unit_id, func, code, proto),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::PlcClock, storage::ModbusSimu, tests::shared_state};
+
+    /// Slave 1 with 10 holding registers holding their address.
+    fn state(identity: DeviceIdentity) -> SharedState {
+        let mut storage = ModbusSimu::new(0, 0, 0, 10);
+        for (address, holding) in storage.holdings.iter_mut().enumerate() {
+            *holding = address as u16;
+        }
+        let mut state =
+            shared_state(vec![(1, storage)], PlcClock::new(false, 100));
+        state.identity = identity;
+        state
+    }
+
+    fn holdings(state: &SharedState) -> Vec<u16> {
+        state.slaves[&1].read().unwrap().holdings.to_vec()
+    }
+
+    #[test]
+    fn mask_write() {
+        let state = state(DeviceIdentity::default());
+        state.slaves[&1].write().unwrap().holdings[4] = 0x12;
+        // example of the Modbus specification
+        let request = [MASK_WRITE_REGISTER, 0, 4, 0, 0xf2, 0, 0x25];
+        assert_eq!(
+            mask_write_register(&state, 1, &request),
+            Ok(request.to_vec())
+        );
+        assert_eq!(holdings(&state)[4], 0x17);
+        // the framed response echoes the framed request
+        let mut frame = vec![0, 5, 0, 0, 0, 8, 1];
+        frame.extend_from_slice(&request);
+        let response = process_extended_request(
+            &state,
+            1,
+            &frame,
+            ModbusProto::TcpUdp,
+            None,
+        )
+        .unwrap();
+        assert_eq!(response, Some(frame));
+    }
+
+    #[test]
+    fn mask_write_exceptions() {
+        let state = state(DeviceIdentity::default());
+        assert_eq!(
+            mask_write_register(
+                &state,
+                1,
+                &[MASK_WRITE_REGISTER, 0, 10, 0, 0, 0, 0]
+            ),
+            Err(MODBUS_ERROR_ILLEGAL_DATA_ADDRESS)
+        );
+        assert_eq!(
+            mask_write_register(
+                &state,
+                1,
+                &[MASK_WRITE_REGISTER, 0, 4, 0, 0, 0]
+            ),
+            Err(MODBUS_ERROR_ILLEGAL_DATA_VALUE)
+        );
+    }
+
+    /// PDU of function 23 writing `values` from `write_reg`.
+    fn read_write_pdu(
+        read_reg: u16,
+        read_count: u16,
+        write_reg: u16,
+        values: &[u16],
+    ) -> Vec<u8> {
+        let mut pdu = vec![READ_WRITE_REGISTERS];
+        pdu.extend_from_slice(&read_reg.to_be_bytes());
+        pdu.extend_from_slice(&read_count.to_be_bytes());
+        pdu.extend_from_slice(&write_reg.to_be_bytes());
+        pdu.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        pdu.push(2 * values.len() as u8);
+        for value in values {
+            pdu.extend_from_slice(&value.to_be_bytes());
+        }
+        pdu
+    }
+
+    #[test]
+    fn read_write_writes_first() {
+        let state = state(DeviceIdentity::default());
+        let pdu = read_write_pdu(1, 3, 2, &[0x1234, 0x5678]);
+        assert_eq!(
+            read_write_registers(&state, 1, &pdu),
+            Ok(vec![READ_WRITE_REGISTERS, 6, 0, 1, 0x12, 0x34, 0x56, 0x78])
+        );
+    }
+
+    #[test]
+    fn read_write_limits() {
+        let state = state(DeviceIdentity::default());
+        for pdu in [
+            read_write_pdu(0, 0, 0, &[1]),
+            read_write_pdu(0, 126, 0, &[1]),
+            read_write_pdu(0, 1, 0, &[]),
+            read_write_pdu(0, 1, 0, &[0; 122]),
+        ] {
+            assert_eq!(
+                read_write_registers(&state, 1, &pdu),
+                Err(MODBUS_ERROR_ILLEGAL_DATA_VALUE)
+            );
+        }
+        // byte count not matching the values
+        let mut pdu = read_write_pdu(0, 1, 0, &[1, 2]);
+        pdu.truncate(pdu.len() - 1);
+        assert_eq!(
+            read_write_registers(&state, 1, &pdu),
+            Err(MODBUS_ERROR_ILLEGAL_DATA_VALUE)
+        );
+    }
+
+    #[test]
+    fn read_write_addresses() {
+        let state = state(DeviceIdentity::default());
+        for pdu in [
+            read_write_pdu(8, 3, 0, &[7]),
+            read_write_pdu(0, 1, 9, &[7, 7]),
+        ] {
+            assert_eq!(
+                read_write_registers(&state, 1, &pdu),
+                Err(MODBUS_ERROR_ILLEGAL_DATA_ADDRESS)
+            );
+        }
+        // nothing was written
+        assert_eq!(holdings(&state), (0..10).collect::<Vec<_>>());
+    }
+
+    /// Objects of a device identification response, and its
+    /// `more follows` and next object id.
+    fn identification(response: &[u8]) -> (Vec<(u8, String)>, u8, u8) {
+        let mut objects = Vec::new();
+        let mut offset = 7;
+        for _ in 0..response[6] {
+            let (id, len) = (response[offset], response[offset + 1] as usize);
+            let value = &response[offset + 2..offset + 2 + len];
+            objects.push((id, String::from_utf8(value.to_vec()).unwrap()));
+            offset += 2 + len;
+        }
+        assert_eq!(offset, response.len());
+        (objects, response[4], response[5])
+    }
+
+    #[test]
+    fn device_identification_stream() {
+        let identity = DeviceIdentity {
+            vendor_name: "v".repeat(240),
+            product_code: "p".repeat(240),
+            ..DeviceIdentity::default()
+        };
+        let state = state(identity.clone());
+        let request = |code, object_id| {
+            let pdu =
+                [ENCAPSULATED_INTERFACE, READ_DEVICE_ID, code, object_id];
+            identification(&read_device_identification(&state, &pdu).unwrap())
+        };
+        // a single 240 bytes object fits in a response
+        assert_eq!(request(1, 0), (vec![(0, identity.vendor_name)], 0xff, 1));
+        assert_eq!(request(1, 1), (vec![(1, identity.product_code)], 0xff, 2));
+        assert_eq!(
+            request(1, 2),
+            (vec![(2, identity.revision.clone())], 0, 0)
+        );
+        // object ids outside the category restart the stream
+        assert_eq!(request(1, 3).0[0].0, 0);
+    }
+
+    #[test]
+    fn device_identification_individual() {
+        let state = state(DeviceIdentity::default());
+        let pdu = [ENCAPSULATED_INTERFACE, READ_DEVICE_ID, 4, 1];
+        let (objects, more_follows, _) =
+            identification(&read_device_identification(&state, &pdu).unwrap());
+        assert_eq!(objects, [(1, "SIMU".to_owned())]);
+        assert_eq!(more_follows, 0);
+        // product name not configured
+        let pdu = [ENCAPSULATED_INTERFACE, READ_DEVICE_ID, 4, 4];
+        assert_eq!(
+            read_device_identification(&state, &pdu),
+            Err(MODBUS_ERROR_ILLEGAL_DATA_ADDRESS)
+        );
+    }
+
+    #[test]
+    fn device_identification_conformity() {
+        let pdu = [ENCAPSULATED_INTERFACE, READ_DEVICE_ID, 1, 0];
+        let state_basic = state(DeviceIdentity::default());
+        assert_eq!(
+            read_device_identification(&state_basic, &pdu).unwrap()[3],
+            0x81
+        );
+        let state_regular = state(DeviceIdentity {
+            product_name: Some("simulator".to_owned()),
+            ..DeviceIdentity::default()
+        });
+        let response =
+            read_device_identification(&state_regular, &pdu).unwrap();
+        assert_eq!(response[3], 0x82);
+        // the basic category leaves the regular objects out
+        assert_eq!(identification(&response).0.len(), 3);
+    }
+}
@@ -0,0 +1,353 @@
+use std::{error::Error, thread, time::Duration};
+
+use rmodbus::ModbusProto;
+use serde::Deserialize;
+
+use crate::{access::Permission, process_request, rng::Rng, SharedState};
+
+/// Misbehaviours applied to the responses of the simulator.
+///
+/// Rates are probabilities in `[0, 1]` drawn independently for every
+/// request; exception codes are picked among `exception_codes`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultConfig {
+    /// delay added before every response, in milliseconds
+    pub latency_ms: u64,
+    /// random extra delay, up to this many milliseconds
+    pub jitter_ms: u64,
+    /// the request is processed but its response is never sent
+    pub drop_rate: f64,
+    /// the request is rejected with a Modbus exception
+    pub exception_rate: f64,
+    pub exception_codes: Vec<u8>,
+    /// only the first half of the response is sent
+    pub truncate_rate: f64,
+    /// the connection is reset instead of answering (TCP only, dropped
+    /// response otherwise)
+    pub reset_rate: f64,
+    /// file overriding these settings for ranges of requests
+    pub schedule: Option<String>,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        Self {
+            latency_ms: 0,
+            jitter_ms: 0,
+            drop_rate: 0.0,
+            exception_rate: 0.0,
+            // illegal data address, slave device busy
+            exception_codes: vec![0x02, 0x06],
+            truncate_rate: 0.0,
+            reset_rate: 0.0,
+            schedule: None,
+        }
+    }
+}
+
+impl FaultConfig {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        match key {
+            "latency_ms" => self.latency_ms = value.parse()?,
+            "jitter_ms" => self.jitter_ms = value.parse()?,
+            "drop_rate" => self.drop_rate = value.parse()?,
+            "exception_rate" => self.exception_rate = value.parse()?,
+            "exception_codes" => self.exception_codes = parse_codes(value)?,
+            "truncate_rate" => self.truncate_rate = value.parse()?,
+            "reset_rate" => self.reset_rate = value.parse()?,
+            _ => Err(format!("unknown fault {key:?}"))?,
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let rates = [
+            ("drop", self.drop_rate),
+            ("exception", self.exception_rate),
+            ("truncate", self.truncate_rate),
+            ("reset", self.reset_rate),
+        ];
+        for (name, rate) in rates {
+            // also refuses NaN
+            if !(0.0..=1.0).contains(&rate) {
+                Err(format!("the {name} rate must be between 0 and 1"))?
+            }
+        }
+        if self.exception_codes.iter().any(|c| *c == 0 || *c >= 0x80) {
+            Err("exception codes must be between 1 and 127")?
+        }
+        Ok(())
+    }
+}
+
+/// Parses a comma separated list of exception codes such as `2,6`.
+pub fn parse_codes(value: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let codes = value
+        .split(',')
+        .map(|c| c.trim().parse())
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| format!("invalid exception codes {value:?}"))?;
+    Ok(codes)
+}
+
+/// Fault settings applied to the requests numbered `from..to` of a session.
+#[derive(Debug)]
+struct ScheduleEntry {
+    from: u64,
+    to: u64,
+    settings: Vec<(String, String)>,
+}
+
+/// Reads a schedule file.
+///
+/// Each non-empty line that is not a `#` comment holds a range of request
+/// numbers (counted from 0 on every connection, UDP socket or serial line)
+/// followed by the settings to override, e.g.
+/// `100 200 drop_rate=0.5 latency_ms=300`.
+fn load_schedule(path: &str) -> Result<Vec<ScheduleEntry>, Box<dyn Error>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read {path:?}: {e}"))?;
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid =
+            || format!("{path}:{}: invalid line {line:?}", number + 1);
+        let mut words = line.split_whitespace();
+        let from = words.next().and_then(|w| w.parse().ok());
+        let to = words.next().and_then(|w| w.parse().ok());
+        let (Some(from), Some(to)) = (from, to) else {
+            Err(invalid())?
+        };
+        let settings = words
+            .map(|w| {
+                w.split_once('=').map(|(k, v)| (k.to_owned(), v.to_owned()))
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        // validate the settings once, at startup
+        let mut check = FaultConfig::default();
+        for (key, value) in &settings {
+            check
+                .set(key, value)
+                .map_err(|e| format!("{}: {e}", invalid()))?;
+        }
+        check
+            .validate()
+            .map_err(|e| format!("{}: {e}", invalid()))?;
+        entries.push(ScheduleEntry { from, to, settings });
+    }
+    Ok(entries)
+}
+
+/// Transport of a fault session, part of the key of its random generator.
+#[derive(Debug, Clone, Copy)]
+pub enum Transport {
+    Tcp,
+    Tls,
+    Udp,
+    Rtu,
+}
+
+/// Fault settings shared by every session of the server.
+#[derive(Debug)]
+pub struct FaultPlan {
+    base: FaultConfig,
+    schedule: Vec<ScheduleEntry>,
+    seed: u64,
+}
+
+impl FaultPlan {
+    pub fn new(base: &FaultConfig, seed: u64) -> Result<Self, Box<dyn Error>> {
+        let schedule = match &base.schedule {
+            Some(path) => load_schedule(path)?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            base: base.clone(),
+            schedule,
+            seed,
+        })
+    }
+
+    /// Fault injector for a new connection, UDP socket or serial line.
+    ///
+    /// The random generator of a session is derived from the seed, its
+    /// transport and its index: the order in which the listener accepted
+    /// the connection, 0 for the UDP socket and the serial line. The same
+    /// sequence of connections and requests thus always meets the same
+    /// faults, whichever transport threads start first.
+    pub fn session(
+        &self,
+        transport: Transport,
+        index: u64,
+    ) -> FaultInjector<'_> {
+        let key = ((transport as u64) << 48).wrapping_add(index);
+        FaultInjector {
+            plan: self,
+            rng: Rng::new(self.seed.wrapping_add(key)),
+            requests: 0,
+        }
+    }
+
+    fn settings(&self, request: u64) -> FaultConfig {
+        let mut settings = self.base.clone();
+        for entry in &self.schedule {
+            if (entry.from..entry.to).contains(&request) {
+                for (key, value) in &entry.settings {
+                    // already validated by load_schedule()
+                    let _ = settings.set(key, value);
+                }
+            }
+        }
+        settings
+    }
+}
+
+/// What to do with one request.
+pub enum Outcome {
+    Respond(Vec<u8>),
+    Silent,
+    Reset,
+}
+
+pub struct FaultInjector<'p> {
+    plan: &'p FaultPlan,
+    rng: Rng,
+    requests: u64,
+}
+
+/// Faults drawn for one request.
+pub struct Faults {
+    /// to wait before answering
+    pub delay: Duration,
+    reset: bool,
+    exception: Option<u8>,
+    drop: bool,
+    truncate: bool,
+}
+
+impl FaultInjector<'_> {
+    /// Draws the faults of the next request.
+    pub fn draw(&mut self) -> Faults {
+        let settings = self.plan.settings(self.requests);
+        self.requests += 1;
+        // always draw the same amount of random values per request so that
+        // changing one rate does not shift the other decisions
+        let jitter =
+            self.rng.next_u64() % settings.jitter_ms.saturating_add(1);
+        let reset = self.rng.chance(settings.reset_rate);
+        let exception = self.rng.chance(settings.exception_rate);
+        let code = self.rng.next_u64() as usize;
+        let drop = self.rng.chance(settings.drop_rate);
+        let truncate = self.rng.chance(settings.truncate_rate);
+        let codes = &settings.exception_codes;
+        Faults {
+            delay: Duration::from_millis(
+                settings.latency_ms.saturating_add(jitter),
+            ),
+            reset,
+            exception: (exception && !codes.is_empty())
+                .then(|| codes[code % codes.len()]),
+            drop,
+            truncate,
+        }
+    }
+
+    /// Processes a request, applying the faults planned for it.
+    pub fn serve(
+        &mut self,
+        state: &SharedState,
+        bytes: &[u8],
+        proto: ModbusProto,
+        permission: Permission,
+    ) -> Result<Outcome, Box<dyn Error>> {
+        let faults = self.draw();
+        if !faults.delay.is_zero() {
+            thread::sleep(faults.delay);
+        }
+        faults.apply(state, bytes, proto, permission)
+    }
+}
+
+impl Faults {
+    /// Processes a request once the delay elapsed.
+    pub fn apply(
+        self,
+        state: &SharedState,
+        bytes: &[u8],
+        proto: ModbusProto,
+        permission: Permission,
+    ) -> Result<Outcome, Box<dyn Error>> {
+        if self.reset {
+            return Ok(Outcome::Reset);
+        }
+        let Some(mut response) =
+            process_request(state, bytes, proto, permission, self.exception)?
+        else {
+            return Ok(Outcome::Silent);
+        };
+        if self.drop {
+            return Ok(Outcome::Silent);
+        }
+        if self.truncate {
+            response.truncate(response.len() / 2);
+        }
+        Ok(Outcome::Respond(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_out_of_range() {
+        for rate in [-0.1, 1.5, 5.0, f64::NAN, f64::INFINITY] {
+            let config = FaultConfig {
+                drop_rate: rate,
+                ..FaultConfig::default()
+            };
+            assert!(config.validate().is_err(), "{rate} accepted");
+            let mut config = FaultConfig::default();
+            config.set("reset_rate", &rate.to_string()).unwrap();
+            assert!(config.validate().is_err(), "{rate} accepted");
+        }
+        for rate in [0.0, 0.5, 1.0] {
+            let config = FaultConfig {
+                truncate_rate: rate,
+                ..FaultConfig::default()
+            };
+            assert!(config.validate().is_ok());
+        }
+    }
+
+    #[test]
+    fn invalid_exception_codes() {
+        assert!(parse_codes("2,x").is_err());
+        for codes in [vec![0], vec![2, 0x80]] {
+            let config = FaultConfig {
+                exception_codes: codes,
+                ..FaultConfig::default()
+            };
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn longest_delay() {
+        let config = FaultConfig {
+            latency_ms: u64::MAX,
+            jitter_ms: u64::MAX,
+            ..FaultConfig::default()
+        };
+        let plan = FaultPlan::new(&config, 1).unwrap();
+        let mut faults = plan.session(Transport::Tcp, 0);
+        for _ in 0..10 {
+            assert_eq!(faults.draw().delay, Duration::from_millis(u64::MAX));
+        }
+    }
+}
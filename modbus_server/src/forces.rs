@@ -0,0 +1,58 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::storage::{Address, ModbusSimu};
+
+/// Values forced by the operator, which override both the PLC program and
+/// the writes of Modbus clients until they are released.
+///
+/// Forced values are written back into the storage after every PLC scan
+/// and every client write. When both are needed, the storage of the slave
+/// is always locked before the forces.
+#[derive(Default)]
+pub struct Forces {
+    values: Mutex<BTreeMap<(u8, Address), u16>>,
+}
+
+impl Forces {
+    pub fn set(&self, unit_id: u8, address: Address, value: u16) {
+        self.values
+            .lock()
+            .unwrap()
+            .insert((unit_id, address), value);
+    }
+
+    /// Releases a forced location and tells whether it was forced.
+    pub fn remove(&self, unit_id: u8, address: Address) -> bool {
+        self.values
+            .lock()
+            .unwrap()
+            .remove(&(unit_id, address))
+            .is_some()
+    }
+
+    /// Releases every forced location of a slave and returns their number.
+    pub fn clear(&self, unit_id: u8) -> usize {
+        let mut values = self.values.lock().unwrap();
+        let before = values.len();
+        values.retain(|(unit, _), _| *unit != unit_id);
+        before - values.len()
+    }
+
+    /// Forced locations of a slave, in address order.
+    pub fn list(&self, unit_id: u8) -> Vec<(Address, u16)> {
+        let values = self.values.lock().unwrap();
+        values
+            .range((unit_id, Address::Coil(0))..)
+            .take_while(|((unit, _), _)| *unit == unit_id)
+            .map(|((_, address), value)| (*address, *value))
+            .collect()
+    }
+
+    /// Writes the forced values of a slave into its storage.
+    pub fn apply(&self, unit_id: u8, storage: &mut ModbusSimu) {
+        for (address, value) in self.list(unit_id) {
+            // forces are checked against the storage when they are set
+            let _ = storage.set(address, value);
+        }
+    }
+}
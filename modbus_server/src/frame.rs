@@ -0,0 +1,228 @@
+use rmodbus::{
+    consts::{
+        MODBUS_ERROR_ILLEGAL_DATA_VALUE, MODBUS_ERROR_ILLEGAL_FUNCTION,
+        MODBUS_GET_COILS, MODBUS_GET_DISCRETES, MODBUS_GET_HOLDINGS,
+        MODBUS_GET_INPUTS, MODBUS_SET_COIL, MODBUS_SET_COILS_BULK,
+        MODBUS_SET_HOLDING, MODBUS_SET_HOLDINGS_BULK,
+    },
+    ErrorKind, ModbusProto,
+};
+
+use crate::extended::is_extended;
+
+/// Largest TCP/UDP request: MBAP header (7 bytes) and a 253 bytes PDU.
+pub const MAX_TCP_ADU_LEN: usize = 260;
+
+/// Largest TCP/UDP PDU rmodbus parses: it refuses MBAP lengths, which
+/// count the unit id, above 250.
+const MAX_RMODBUS_TCP_PDU_LEN: usize = 249;
+
+/// Standard Modbus CRC of RTU frames.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffff;
+    for byte in bytes {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Checks the framing of a request and returns its unit id and its PDU,
+/// which holds at least the function code.
+pub fn split_frame(
+    bytes: &[u8],
+    proto: ModbusProto,
+) -> Result<(u8, &[u8]), ErrorKind> {
+    match proto {
+        ModbusProto::TcpUdp => {
+            if bytes.len() < 8
+                || bytes[2..4] != [0, 0]
+                || usize::from(u16::from_be_bytes([bytes[4], bytes[5]]))
+                    != bytes.len() - 6
+            {
+                return Err(ErrorKind::FrameBroken);
+            }
+            Ok((bytes[6], &bytes[7..]))
+        }
+        _ => {
+            if bytes.len() < 4 {
+                return Err(ErrorKind::FrameBroken);
+            }
+            let (frame, crc) = bytes.split_at(bytes.len() - 2);
+            if crc16(frame).to_le_bytes() != crc {
+                return Err(ErrorKind::FrameCRCError);
+            }
+            Ok((frame[0], &frame[1..]))
+        }
+    }
+}
+
+/// Checks a PDU before it is processed and returns the exception code to
+/// reject it with, if any.
+///
+/// rmodbus indexes the frame without checking its length, rejects short
+/// frames of the functions it does not know instead of answering them,
+/// and rejects TCP/UDP writes of 122 registers or more, which the standard
+/// allows.
+pub fn check_pdu(pdu: &[u8], proto: ModbusProto) -> Option<u8> {
+    let valid = match pdu[0] {
+        MODBUS_GET_COILS | MODBUS_GET_DISCRETES | MODBUS_GET_HOLDINGS
+        | MODBUS_GET_INPUTS | MODBUS_SET_COIL | MODBUS_SET_HOLDING => {
+            pdu.len() == 5
+        }
+        MODBUS_SET_COILS_BULK | MODBUS_SET_HOLDINGS_BULK => {
+            pdu.len() >= 6
+                && pdu.len() == 6 + pdu[5] as usize
+                && (proto != ModbusProto::TcpUdp
+                    || pdu.len() <= MAX_RMODBUS_TCP_PDU_LEN)
+        }
+        func if is_extended(func) => true,
+        _ => return Some(MODBUS_ERROR_ILLEGAL_FUNCTION),
+    };
+    (!valid).then_some(MODBUS_ERROR_ILLEGAL_DATA_VALUE)
+}
+
+/// Wraps a response PDU in the framing of the request.
+pub fn frame_response(
+    request: &[u8],
+    unit_id: u8,
+    pdu: &[u8],
+    proto: ModbusProto,
+) -> Vec<u8> {
+    let mut response = Vec::with_capacity(pdu.len() + 7);
+    if proto == ModbusProto::TcpUdp {
+        // same transaction and protocol ids as the request
+        response.extend_from_slice(&request[..4]);
+        response.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+    }
+    response.push(unit_id);
+    response.extend_from_slice(pdu);
+    if proto != ModbusProto::TcpUdp {
+        let crc = crc16(&response);
+        response.extend_from_slice(&crc.to_le_bytes());
+    }
+    response
+}
+
+/// Exception response to a request.
+pub fn exception_response(
+    request: &[u8],
+    unit_id: u8,
+    func: u8,
+    code: u8,
+    proto: ModbusProto,
+) -> Vec<u8> {
+    frame_response(request, unit_id, &[func | 0x80, code], proto)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// PDU of a write of `count` registers.
+    fn write_registers(count: u8) -> Vec<u8> {
+        let mut pdu =
+            vec![MODBUS_SET_HOLDINGS_BULK, 0, 0, 0, count, 2 * count];
+        pdu.resize(6 + 2 * count as usize, 0);
+        pdu
+    }
+
+    #[test]
+    fn split_tcp_frame() {
+        let frame = [0, 7, 0, 0, 0, 6, 3, 3, 0, 1, 0, 2];
+        assert_eq!(
+            split_frame(&frame, ModbusProto::TcpUdp),
+            Ok((3, &frame[7..]))
+        );
+        // another protocol id, length not matching, no function code
+        for frame in [
+            &[0, 7, 0, 1, 0, 6, 3, 3, 0, 1, 0, 2][..],
+            &[0, 7, 0, 0, 0, 5, 3, 3, 0, 1, 0, 2],
+            &[0, 7, 0, 0, 0, 1, 3],
+        ] {
+            assert_eq!(
+                split_frame(frame, ModbusProto::TcpUdp),
+                Err(ErrorKind::FrameBroken)
+            );
+        }
+    }
+
+    #[test]
+    fn split_rtu_frame() {
+        let mut frame = vec![3, 3, 0, 1, 0, 2];
+        frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+        assert_eq!(
+            split_frame(&frame, ModbusProto::Rtu),
+            Ok((3, &frame[1..6]))
+        );
+        frame[7] ^= 1;
+        assert_eq!(
+            split_frame(&frame, ModbusProto::Rtu),
+            Err(ErrorKind::FrameCRCError)
+        );
+        assert_eq!(
+            split_frame(&[3, 3, 0], ModbusProto::Rtu),
+            Err(ErrorKind::FrameBroken)
+        );
+    }
+
+    #[test]
+    fn check_pdu_length() {
+        let tcp = ModbusProto::TcpUdp;
+        assert_eq!(check_pdu(&[MODBUS_GET_HOLDINGS, 0, 0, 0, 1], tcp), None);
+        assert_eq!(
+            check_pdu(&[MODBUS_GET_HOLDINGS, 0, 0, 0], tcp),
+            Some(MODBUS_ERROR_ILLEGAL_DATA_VALUE)
+        );
+        assert_eq!(
+            check_pdu(&[MODBUS_SET_COIL, 0, 0, 0xff, 0, 0], tcp),
+            Some(MODBUS_ERROR_ILLEGAL_DATA_VALUE)
+        );
+        // byte count not matching the data
+        let mut pdu = write_registers(2);
+        pdu.pop();
+        assert_eq!(
+            check_pdu(&pdu, tcp),
+            Some(MODBUS_ERROR_ILLEGAL_DATA_VALUE)
+        );
+        assert_eq!(
+            check_pdu(&[MODBUS_SET_COILS_BULK, 0, 0, 0], tcp),
+            Some(MODBUS_ERROR_ILLEGAL_DATA_VALUE)
+        );
+    }
+
+    #[test]
+    fn check_pdu_function() {
+        let tcp = ModbusProto::TcpUdp;
+        assert_eq!(
+            check_pdu(&[0x41], tcp),
+            Some(MODBUS_ERROR_ILLEGAL_FUNCTION)
+        );
+        // the extended functions check their frames themselves
+        assert_eq!(check_pdu(&[0x2b], tcp), None);
+    }
+
+    #[test]
+    fn check_pdu_rmodbus_limit() {
+        assert_eq!(
+            check_pdu(&write_registers(121), ModbusProto::TcpUdp),
+            None
+        );
+        for count in [122, 123] {
+            assert_eq!(
+                check_pdu(&write_registers(count), ModbusProto::TcpUdp),
+                Some(MODBUS_ERROR_ILLEGAL_DATA_VALUE)
+            );
+            assert_eq!(
+                check_pdu(&write_registers(count), ModbusProto::Rtu),
+                None
+            );
+        }
+    }
+}
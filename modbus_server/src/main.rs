@@ -1,214 +1,967 @@
+mod access;
+mod capture;
+mod clock;
+mod config;
+mod control;
+mod extended;
+mod faults;
+mod forces;
+mod frame;
+mod plc;
+mod rng;
+mod rtu;
+mod signals;
+mod snapshot;
+mod stats;
+mod storage;
+mod tls;
+mod tokio_server;
+mod udp;
+
 use std::{
+    collections::{BTreeMap, BTreeSet},
     error::Error,
     io::{ErrorKind, Read, Write},
-    net::{Ipv4Addr, TcpListener, TcpStream},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
     ops::{Deref, DerefMut},
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, RwLock,
     },
     thread,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use rmodbus::{
-    guess_request_frame_len,
-    server::{context::ModbusContext, storage::ModbusStorage, ModbusFrame},
-    ModbusFrameBuf, ModbusProto,
+    consts::{MODBUS_ERROR_ILLEGAL_DATA_VALUE, MODBUS_ERROR_ILLEGAL_FUNCTION},
+    server::ModbusFrame,
+    ModbusProto,
 };
 
-type ModbusSimu = ModbusStorage<20, 0, 0, 5>;
+use socket2::SockRef;
+
+use access::{is_write, AccessConfig, Permission};
+use capture::Capture;
+use clock::PlcClock;
+use config::{Config, SlaveConfig};
+use extended::{is_extended, process_extended_request, DeviceIdentity};
+use faults::{FaultInjector, FaultPlan, Outcome, Transport};
+use forces::Forces;
+use frame::{check_pdu, exception_response, split_frame, MAX_TCP_ADU_LEN};
+use plc::{program_by_name, PlcProgram};
+use signals::SignalBank;
+use stats::Stats;
+use storage::ModbusSimu;
 
 struct SharedState {
-    unit_id: u8,
-    context: RwLock<ModbusSimu>,
+    /// storage of each hosted slave, by unit id
+    slaves: BTreeMap<u8, RwLock<ModbusSimu>>,
+    /// unit id of the slave configured first
+    main_unit_id: u8,
+    forces: Forces,
+    access: AccessConfig,
+    identity: DeviceIdentity,
+    /// pcap file recording the TCP and UDP traffic
+    capture: Option<Capture>,
+    faults: FaultPlan,
+    clock: PlcClock,
+    stats: Stats,
+    /// clients of the TCP, TLS and async listeners together
+    clients: AtomicUsize,
     must_quit: AtomicBool,
 }
 
-fn recv_request_bytes<'b>(
-    stream: &mut TcpStream,
-    buffer: &'b mut ModbusFrameBuf,
-) -> Result<&'b [u8], Box<dyn Error>> {
-    if let Err(e) = stream.read_exact(&mut buffer[..8]) {
-        return match e.kind() {
-            ErrorKind::UnexpectedEof => Ok(&buffer[0..0]),
-            _ => Err(e)?,
+/// How often blocked reads wake up to check `must_quit`.
+const POLL: Duration = Duration::from_millis(200);
+
+/// Time limits of a TCP connection.
+#[derive(Clone, Copy)]
+struct Timeouts {
+    /// to receive the rest of a frame once it started
+    read: Duration,
+    /// to receive the start of the next frame
+    idle: Option<Duration>,
+}
+
+impl Timeouts {
+    fn new(read: Duration, access: &AccessConfig) -> Self {
+        Self {
+            read,
+            idle: access.idle_timeout_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+/// Fills `buffer` from the stream.
+///
+/// Waiting for the first byte of a frame (`started` is `None`) ends with
+/// `must_quit` or the end of the connection, both reported as `Ok(false)`,
+/// or with the idle timeout; once a frame started, it must be complete
+/// within the read timeout.
+fn read_frame_part(
+    stream: &mut impl Read,
+    buffer: &mut [u8],
+    started: &mut Option<Instant>,
+    timeouts: Timeouts,
+    must_quit: &AtomicBool,
+) -> Result<bool, Box<dyn Error>> {
+    let waiting = Instant::now();
+    let mut filled = 0;
+    while filled < buffer.len() {
+        if must_quit.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        match (*started, timeouts.idle) {
+            (Some(started), _) if started.elapsed() > timeouts.read => {
+                Err(format!(
+                    "incomplete frame after {} ms",
+                    timeouts.read.as_millis()
+                ))?
+            }
+            (None, Some(idle)) if waiting.elapsed() > idle => {
+                Err(format!("idle for {} ms", idle.as_millis()))?
+            }
+            _ => {}
+        }
+        match stream.read(&mut buffer[filled..]) {
+            Ok(0) if started.is_none() => return Ok(false), // EOF
+            Ok(0) => Err("connection closed in the middle of a frame")?,
+            Ok(count) => {
+                filled += count;
+                started.get_or_insert_with(Instant::now);
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock
+                        | ErrorKind::TimedOut
+                        | ErrorKind::Interrupted
+                ) => {}
+            Err(e) => Err(e)?,
+        }
+    }
+    Ok(true)
+}
+
+/// What was read from a TCP client.
+enum Received<'b> {
+    Request(&'b [u8]),
+    /// frame which cannot be processed, and the response to it
+    Rejected(Vec<u8>, Vec<u8>),
+    /// frame to ignore, not a Modbus request
+    Skipped(Vec<u8>),
+    Closed,
+}
+
+/// Number of bytes following the MBAP header of a frame.
+fn mbap_body_length(header: &[u8]) -> usize {
+    // the length includes the unit id, which is part of the header
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    length.saturating_sub(1)
+}
+
+/// Tells what to do with a frame whose header is at the start of `buffer`
+/// and whose body was read after it, or into `drained` when it was too
+/// large for the buffer.
+///
+/// Like the requests, frames too large for broadcasts or for units this
+/// server does not host are never answered.
+fn classify_frame<'b>(
+    buffer: &'b [u8; MAX_TCP_ADU_LEN],
+    body_length: usize,
+    drained: &[u8],
+    state: &SharedState,
+) -> Received<'b> {
+    let header = &buffer[..7];
+    // the whole frame as received, for the capture
+    let frame = || {
+        let body = if drained.is_empty() {
+            &buffer[7..7 + body_length]
+        } else {
+            drained
         };
+        [header, body].concat()
+    };
+    if header[2..4] != [0, 0] || body_length == 0 {
+        // another protocol, or no function code
+        return Received::Skipped(frame());
+    }
+    if !drained.is_empty() {
+        let (unit_id, func) = (header[6], drained[0]);
+        if !state.slaves.contains_key(&unit_id) {
+            // broadcast (no slave has unit id 0 or 255) or another unit
+            return Received::Skipped(frame());
+        }
+        let response = exception_response(
+            header,
+            unit_id,
+            func,
+            MODBUS_ERROR_ILLEGAL_DATA_VALUE,
+            ModbusProto::TcpUdp,
+        );
+        return Received::Rejected(frame(), response);
+    }
+    Received::Request(&buffer[..7 + body_length])
+}
+
+/// Reads one MBAP-framed request.
+///
+/// The length announced by the header is always consumed, so that a bad
+/// frame does not shift the following ones.
+fn recv_request_bytes<'b>(
+    stream: &mut impl Read,
+    buffer: &'b mut [u8; MAX_TCP_ADU_LEN],
+    timeouts: Timeouts,
+    state: &SharedState,
+) -> Result<Received<'b>, Box<dyn Error>> {
+    let must_quit = &state.must_quit;
+    let mut started = None;
+    let (header, body) = buffer.split_at_mut(7);
+    if !read_frame_part(stream, header, &mut started, timeouts, must_quit)? {
+        return Ok(Received::Closed);
+    }
+    let body_length = mbap_body_length(header);
+    let mut drained = Vec::new();
+    let body = if body_length > body.len() {
+        drained.resize(body_length, 0);
+        &mut drained[..]
+    } else {
+        &mut body[..body_length]
+    };
+    if !read_frame_part(stream, body, &mut started, timeouts, must_quit)? {
+        return Ok(Received::Closed);
+    }
+    Ok(classify_frame(buffer, body_length, &drained, state))
+}
+
+fn process_slave_request(
+    state: &SharedState,
+    unit_id: u8,
+    bytes: &[u8],
+    proto: ModbusProto,
+    exception: Option<u8>,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let func = match proto {
+        ModbusProto::TcpUdp => bytes.get(7),
+        _ => bytes.get(1),
+    };
+    if func.is_some_and(|func| is_extended(*func)) {
+        return process_extended_request(
+            state, unit_id, bytes, proto, exception,
+        );
+    }
+    let mut response = Vec::new();
+    let mut frame = ModbusFrame::new(unit_id, bytes, proto, &mut response);
+    frame.parse()?;
+    let context = &state.slaves[&unit_id];
+    if let (Some(code), true) = (exception, frame.response_required) {
+        // finalize_response() turns the frame into an exception response
+        frame.error = code;
+    } else if frame.processing_required {
+        if frame.readonly {
+            let guard = context.read().unwrap();
+            frame.process_read(guard.deref())?;
+        } else {
+            let mut guard = context.write().unwrap();
+            frame.process_write(guard.deref_mut())?;
+            // forced values win over client writes
+            state.forces.apply(unit_id, &mut guard);
+        }
+    }
+    if !frame.response_required {
+        return Ok(None);
+    }
+    frame.finalize_response()?;
+    Ok(Some(response))
+}
+
+/// Processes one request frame and returns the response to send back, if
+/// the request calls for one.
+///
+/// The request is routed to the slave matching its unit id; broadcasts
+/// (unit id 0 or 255) reach every slave and are never answered, and
+/// requests for unknown unit ids are ignored. Unknown functions and
+/// requests whose length does not match their function are rejected with
+/// an exception, as are writes from read-only clients.
+/// When an exception code is given, the request is rejected with it instead
+/// of being processed.
+fn process_request(
+    state: &SharedState,
+    bytes: &[u8],
+    proto: ModbusProto,
+    permission: Permission,
+    exception: Option<u8>,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let result = route_request(state, bytes, proto, permission, exception);
+    match (&result, split_frame(bytes, proto)) {
+        (Ok(_), Ok((unit_id, _)))
+            if unit_id != 0
+                && unit_id != 255
+                && !state.slaves.contains_key(&unit_id) =>
+        {
+            state.stats.ignored_request()
+        }
+        (Ok(response), Ok((_, pdu))) => {
+            let func_offset = if proto == ModbusProto::TcpUdp { 7 } else { 1 };
+            let exception = response.as_ref().is_some_and(|response| {
+                response.get(func_offset).is_some_and(|func| *func >= 0x80)
+            });
+            state.stats.request(pdu[0], exception);
+        }
+        _ => state.stats.invalid_frame(),
+    }
+    result
+}
+
+fn route_request(
+    state: &SharedState,
+    bytes: &[u8],
+    proto: ModbusProto,
+    permission: Permission,
+    exception: Option<u8>,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let (unit_id, pdu) = split_frame(bytes, proto)?;
+    let func = pdu[0];
+    if func >= 0x80 {
+        // exception function codes are never requests
+        Err(rmodbus::ErrorKind::FrameBroken)?
+    }
+    let rejection = check_pdu(pdu, proto).or_else(|| {
+        // read-only clients are told the write functions do not exist
+        (permission == Permission::ReadOnly && is_write(func))
+            .then_some(MODBUS_ERROR_ILLEGAL_FUNCTION)
+    });
+    if unit_id == 0 || unit_id == 255 {
+        if rejection.is_some() {
+            return Ok(None);
+        }
+        for unit_id in state.slaves.keys() {
+            process_slave_request(state, *unit_id, bytes, proto, None)?;
+        }
+        return Ok(None);
+    }
+    if !state.slaves.contains_key(&unit_id) {
+        return Ok(None);
+    }
+    if let Some(code) = rejection {
+        return Ok(Some(exception_response(
+            bytes, unit_id, func, code, proto,
+        )));
     }
-    let length =
-        guess_request_frame_len(&buffer[..8], ModbusProto::TcpUdp)? as usize;
-    stream.read_exact(&mut buffer[8..length])?;
-    Ok(&buffer[..length])
+    process_slave_request(state, unit_id, bytes, proto, exception)
 }
 
+/// Serves one client until it disconnects or the server stops.
+///
+/// `stream` carries the requests and responses, either `socket` itself or
+/// a TLS session running on it.
 fn modbus_dialogue(
-    mut stream: TcpStream,
+    stream: &mut (impl Read + Write),
+    socket: &TcpStream,
+    timeouts: Timeouts,
+    permission: Permission,
+    mut faults: FaultInjector,
     state: &SharedState,
 ) -> Result<(), Box<dyn Error>> {
+    // wake up regularly to notice must_quit
+    socket.set_read_timeout(Some(POLL))?;
+    let mut capture = match &state.capture {
+        Some(capture) => {
+            capture.tcp_session(socket.peer_addr()?, socket.local_addr()?)
+        }
+        None => None,
+    };
+    let client = socket.peer_addr()?.ip().to_string();
+    let mut buffer = [0; MAX_TCP_ADU_LEN];
     while !state.must_quit.load(Ordering::Relaxed) {
-        let mut buffer = [0; 256];
-        let bytes = recv_request_bytes(&mut stream, &mut buffer)?;
-        if bytes.is_empty() {
-            break; // EOF
-        }
-        let mut response = Vec::new();
-        let mut frame = ModbusFrame::new(
-            state.unit_id,
+        let bytes =
+            match recv_request_bytes(stream, &mut buffer, timeouts, state)? {
+                Received::Request(bytes) => bytes,
+                Received::Rejected(request, response) => {
+                    state.stats.client_request(&client);
+                    state.stats.invalid_frame();
+                    if let Some(capture) = &mut capture {
+                        capture.request(&request);
+                        capture.response(&response);
+                    }
+                    stream.write_all(&response)?;
+                    continue;
+                }
+                Received::Skipped(frame) => {
+                    state.stats.invalid_frame();
+                    if let Some(capture) = &mut capture {
+                        capture.request(&frame);
+                    }
+                    continue;
+                }
+                Received::Closed => break,
+            };
+        state.stats.client_request(&client);
+        if let Some(capture) = &mut capture {
+            capture.request(bytes);
+        }
+        let outcome = match faults.serve(
+            state,
             bytes,
             ModbusProto::TcpUdp,
-            &mut response,
-        );
-        frame.parse()?;
-        if frame.processing_required {
-            if frame.readonly {
-                let guard = state.context.read().unwrap();
-                frame.process_read(guard.deref())?;
-            } else {
-                let mut guard = state.context.write().unwrap();
-                frame.process_write(guard.deref_mut())?;
+            permission,
+        ) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                // a bad frame does not end the session
+                eprintln!("invalid tcp frame {:02x?}: {:?}", bytes, e);
+                continue;
+            }
+        };
+        match outcome {
+            Outcome::Respond(response) => {
+                if let Some(capture) = &mut capture {
+                    capture.response(&response);
+                }
+                stream.write_all(&response)?
+            }
+            Outcome::Silent => {}
+            Outcome::Reset => {
+                if let Some(capture) = &mut capture {
+                    capture.reset();
+                }
+                // closing with a zero linger time sends a RST
+                SockRef::from(socket).set_linger(Some(Duration::ZERO))?;
+                break;
             }
-        }
-        if frame.response_required {
-            frame.finalize_response()?;
-            stream.write_all(response.as_slice())?;
         }
     }
     Ok(())
 }
 
-fn modbus_tcp_server(
-    tcp_port: u16,
-    state: Arc<SharedState>,
+/// Place of a connected client in the `max_connections` limit, freed when
+/// the client leaves.
+struct ClientSlot(Arc<SharedState>);
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.0.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Decides whether a new TCP client is served and with which permission;
+/// the connection limit applies to every listener together.
+fn admit(
+    state: &Arc<SharedState>,
+    addr: SocketAddr,
+) -> Option<(Permission, ClientSlot)> {
+    let Some(permission) = state.access.permission(addr.ip()) else {
+        println!("connection from {:?} refused", addr);
+        return None;
+    };
+    let limit = state.access.max_connections.unwrap_or(usize::MAX);
+    let taken = state.clients.fetch_update(
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+        |clients| (clients < limit).then_some(clients + 1),
+    );
+    if taken.is_err() {
+        println!(
+            "connection from {:?} refused, {} clients already",
+            addr, limit
+        );
+        return None;
+    }
+    println!("new connection from {:?} ({:?})", addr, permission);
+    Some((permission, ClientSlot(Arc::clone(state))))
+}
+
+/// Accepts the clients of `listener` until the server stops, and runs
+/// `serve(stream, permission, connection)` on a thread for each one;
+/// `connection` numbers the clients of this listener.
+fn accept_clients(
+    listener: TcpListener,
+    state: &Arc<SharedState>,
+    serve: impl Fn(TcpStream, Permission, u64) -> Result<(), Box<dyn Error>>
+        + Send
+        + Sync
+        + 'static,
 ) -> Result<(), Box<dyn Error>> {
-    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, tcp_port))?;
+    let serve = Arc::new(serve);
+    let mut clients: Vec<thread::JoinHandle<()>> = Vec::new();
+    let mut connections = 0;
     listener.set_nonblocking(true)?;
-    println!(
-        "modbus tcp server waiting for connections on port '{}'",
-        tcp_port
-    );
     while !state.must_quit.load(Ordering::Relaxed) {
         match listener.accept() {
             Ok((stream, addr)) => {
-                println!("new connection from {:?}", addr);
+                // forget the clients which already left
+                clients.retain(|client| !client.is_finished());
+                let Some((permission, slot)) = admit(state, addr) else {
+                    continue;
+                };
                 stream.set_nonblocking(false)?;
-                thread::spawn({
-                    let state = Arc::clone(&state);
+                let connection = connections;
+                connections += 1;
+                clients.push(thread::spawn({
+                    let state = Arc::clone(state);
+                    let serve = Arc::clone(&serve);
                     move || {
-                        if let Err(e) =
-                            modbus_dialogue(stream, state.as_ref())
-                        {
+                        let _slot = slot;
+                        if let Err(e) = serve(stream, permission, connection) {
+                            state.stats.connection_error();
                             eprintln!("{:?}", e);
                         }
                         println!("client {:?} disconnected", addr);
                     }
-                });
+                }));
             }
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    thread::sleep(Duration::from_millis(200));
-                } else {
-                    println!("ERROR {:?}", e);
-                    state.must_quit.store(true, Ordering::Relaxed);
-                    Err(e)?
-                }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(200));
             }
+            Err(e) => Err(e)?,
         }
     }
+    // let the clients notice must_quit and close their connection
+    for client in clients {
+        let _ = client.join();
+    }
     Ok(())
 }
 
+fn modbus_tcp_server(
+    tcp_port: u16,
+    read_timeout: Duration,
+    state: Arc<SharedState>,
+) -> Result<(), Box<dyn Error>> {
+    let timeouts = Timeouts::new(read_timeout, &state.access);
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, tcp_port))?;
+    println!(
+        "modbus tcp server waiting for connections on port '{}'",
+        tcp_port
+    );
+    accept_clients(listener, &state, {
+        let state = Arc::clone(&state);
+        move |stream, permission, connection| {
+            let faults = state.faults.session(Transport::Tcp, connection);
+            modbus_dialogue(
+                &mut &stream,
+                &stream,
+                timeouts,
+                permission,
+                faults,
+                &state,
+            )
+        }
+    })
+}
+
 fn now_utc_ms() -> Result<u64, Box<dyn std::error::Error>> {
     let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
     Ok(now.as_millis() as u64)
 }
 
+/// Runs the programs of every slave once per PLC period (or per step of the
+/// stepped clock), in order, then applies the forced values.
 fn run_plc(
     state: &SharedState,
-    ms_div: u64,
+    programs: &mut [(u8, Box<dyn PlcProgram>)],
+    restored: &BTreeSet<u8>,
 ) -> Result<(), Box<dyn Error>> {
-    let (coil_count, holding_count) = {
-        let context = state.context.read().unwrap();
-        (context.coils.len(), context.holdings.len())
-    };
-    let mut counter = 0;
+    for (unit_id, program) in programs.iter_mut() {
+        program.init(
+            &mut state.slaves[unit_id].write().unwrap(),
+            restored.contains(unit_id),
+        )?;
+    }
     let mut last_utc_ms = now_utc_ms()?;
-    let mut coils = vec![false; coil_count];
-    let mut holdings = vec![0; holding_count];
     while !state.must_quit.load(Ordering::Relaxed) {
-        let utc_ms = now_utc_ms()?;
-        if utc_ms / ms_div == last_utc_ms / ms_div {
-            thread::sleep(Duration::from_micros(250));
+        if state.clock.is_paused() {
+            thread::sleep(Duration::from_millis(10));
             continue;
         }
-        if let Ok(mut context) = state.context.try_write() {
-            let low = coil_count / 3;
-            let high = coil_count - low;
-            let mut current = counter % (2 * (low - 1));
-            if current >= low {
-                current = 2 * (low - 1) - current;
-            }
-            for (i, c) in &mut coils[..low].iter_mut().enumerate() {
-                *c = i == current;
-            }
-            let mut current = counter % (2 * high);
-            if current > high {
-                current = 2 * high - current;
-            }
-            for (i, c) in &mut coils[low..].iter_mut().enumerate() {
-                *c = i < current;
+        if state.clock.is_stepped() {
+            if !state.clock.wait_step() {
+                continue;
             }
-            context.set_coils_bulk(0, &coils)?;
-            for (i, h) in holdings.iter_mut().enumerate() {
-                *h = (counter / (i + 1)) as u16;
+        } else {
+            let utc_ms = now_utc_ms()?;
+            let ms_div = state.clock.period_ms();
+            if utc_ms / ms_div == last_utc_ms / ms_div {
+                thread::sleep(Duration::from_micros(250));
+                continue;
             }
-            context.set_holdings_bulk(0, &holdings)?;
-            counter += 1;
             last_utc_ms = utc_ms;
         }
+        let started = Instant::now();
+        let time = state.clock.scan_time();
+        for (unit_id, context) in &state.slaves {
+            let mut context = context.write().unwrap();
+            for (_, program) in
+                programs.iter_mut().filter(|(id, _)| id == unit_id)
+            {
+                program.scan(time, &mut context)?;
+            }
+            // under the same lock, clients never see a forced value unforced
+            state.forces.apply(*unit_id, &mut context);
+        }
+        state.stats.scan_done(started.elapsed());
+        state.clock.scan_done(time);
     }
     Ok(())
 }
 
+fn new_slave_context(slave: &SlaveConfig) -> ModbusSimu {
+    let mut context = ModbusSimu::new(
+        slave.coils as usize,
+        slave.discretes as usize,
+        slave.inputs as usize,
+        slave.holdings as usize,
+    );
+    context.advertise_sizes();
+    context
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
-    let ms_div = if std::env::args().any(|a| a == "fast") {
-        1
+    let config = Config::from_args(std::env::args().skip(1))?;
+    let ms_div = config.ms_div;
+    let tcp_port = config.tcp_port;
+    let read_timeout = Duration::from_millis(config.read_timeout_ms);
+    let mut slaves = BTreeMap::new();
+    let mut programs = Vec::new();
+    for slave in config.all_slaves() {
+        let context = new_slave_context(&slave);
+        println!("modbus unit id: {}", slave.unit_id);
+        println!("  number of coils: {}", context.coils.len());
+        println!("  number of discrete inputs: {}", context.discretes.len());
+        println!("  number of input registers: {}", context.inputs.len());
+        println!("  number of holding registers: {}", context.holdings.len());
+        println!("  PLC program: {}", slave.program);
+        let program = program_by_name(&slave.program, &config)?;
+        programs.push((slave.unit_id, program));
+        for signal in &slave.signals {
+            println!("  signal on {}: {:?}", signal.target, signal.kind);
+        }
+        if !slave.signals.is_empty() {
+            let signals = SignalBank::new(&slave.signals, config.seed)?;
+            programs.push((slave.unit_id, Box::new(signals)));
+        }
+        slaves.insert(slave.unit_id, RwLock::new(context));
+    }
+    if config.stepped {
+        println!("stepped clock, each step simulates {} ms", ms_div);
     } else {
-        100
-    };
-    let unit_id = 1;
-    let tcp_port = 55022;
-    let mut context = ModbusSimu::new();
-    context.set_holding(0, context.coils.len() as u16)?;
-    context.set_holding(1, context.discretes.len() as u16)?;
-    context.set_holding(2, context.inputs.len() as u16)?;
-    context.set_holding(3, context.holdings.len() as u16)?;
-    println!("modbus unit id: {}", unit_id);
-    println!("number of coils: {}", context.get_holding(0)?);
-    println!("number of discrete inputs: {}", context.get_holding(1)?);
-    println!("number of input registers: {}", context.get_holding(2)?);
-    println!("number of holding registers: {}", context.get_holding(3)?);
-    println!("changing state every {} ms", ms_div);
+        println!("changing state every {} ms", ms_div);
+    }
     let state = Arc::new(SharedState {
-        unit_id,
-        context: RwLock::new(context),
+        slaves,
+        main_unit_id: config.unit_id,
+        forces: Forces::default(),
+        access: config.access.clone(),
+        capture: match &config.capture {
+            Some(path) => Some(Capture::create(path)?),
+            None => None,
+        },
+        identity: config.identity.clone(),
+        faults: FaultPlan::new(&config.faults, config.seed)?,
+        clock: PlcClock::new(config.stepped, ms_div),
+        stats: Stats::default(),
+        clients: AtomicUsize::new(0),
         must_quit: AtomicBool::new(false),
     });
-    let th = thread::spawn({
+    ctrlc::set_handler({
         let state = Arc::clone(&state);
         move || {
-            if let Err(e) = modbus_tcp_server(tcp_port, state) {
+            if state.must_quit.swap(true, Ordering::Relaxed) {
+                // a second signal does not wait for the clean shutdown
+                std::process::exit(130);
+            }
+            println!("stopping, interrupt again to quit immediately");
+        }
+    })?;
+    let restored = match &config.snapshot {
+        Some(path) => {
+            let restored = snapshot::restore(path, &state)?;
+            println!(
+                "saving snapshots every {} ms",
+                config.snapshot_interval_ms
+            );
+            restored
+        }
+        None => BTreeSet::new(),
+    };
+    let tcp_server = if config.async_tcp {
+        tokio_server::modbus_tcp_server_async
+    } else {
+        modbus_tcp_server
+    };
+    let mut servers = vec![thread::spawn({
+        let state = Arc::clone(&state);
+        move || {
+            if let Err(e) =
+                tcp_server(tcp_port, read_timeout, Arc::clone(&state))
+            {
+                state.must_quit.store(true, Ordering::Relaxed);
                 panic!("{}", e);
             }
         }
-    });
-    let plc_result = run_plc(&state, ms_div);
+    })];
+    if config.tls.port.is_some() {
+        servers.push(thread::spawn({
+            let state = Arc::clone(&state);
+            let tls = config.tls.clone();
+            move || {
+                if let Err(e) = tls::modbus_tls_server(
+                    tls,
+                    read_timeout,
+                    Arc::clone(&state),
+                ) {
+                    state.must_quit.store(true, Ordering::Relaxed);
+                    panic!("{}", e);
+                }
+            }
+        }));
+    }
+    if let Some(udp_port) = config.udp_port {
+        servers.push(thread::spawn({
+            let state = Arc::clone(&state);
+            move || {
+                if let Err(e) =
+                    udp::modbus_udp_server(udp_port, Arc::clone(&state))
+                {
+                    state.must_quit.store(true, Ordering::Relaxed);
+                    panic!("{}", e);
+                }
+            }
+        }));
+    }
+    if let Some(device) = config.rtu_device.clone() {
+        servers.push(thread::spawn({
+            let state = Arc::clone(&state);
+            move || {
+                if let Err(e) = rtu::modbus_rtu_server(
+                    &device,
+                    config.baud_rate,
+                    config.parity,
+                    Arc::clone(&state),
+                ) {
+                    state.must_quit.store(true, Ordering::Relaxed);
+                    panic!("{}", e);
+                }
+            }
+        }));
+    }
+    if let Some(control_port) = config.control_port {
+        servers.push(thread::spawn({
+            let state = Arc::clone(&state);
+            move || {
+                if let Err(e) =
+                    control::control_server(control_port, Arc::clone(&state))
+                {
+                    state.must_quit.store(true, Ordering::Relaxed);
+                    panic!("{}", e);
+                }
+            }
+        }));
+    }
+    if let Some(stats_port) = config.stats_port {
+        servers.push(thread::spawn({
+            let state = Arc::clone(&state);
+            move || {
+                if let Err(e) = stats::metrics_server(stats_port, &state) {
+                    state.must_quit.store(true, Ordering::Relaxed);
+                    panic!("{}", e);
+                }
+            }
+        }));
+    }
+    if let Some(start) = config.stats_registers {
+        println!(
+            "statistics in input registers %IW{}..%IW{}",
+            start,
+            start + stats::STATS_REGISTERS - 1
+        );
+        servers.push(thread::spawn({
+            let state = Arc::clone(&state);
+            move || {
+                if let Err(e) = stats::update_registers(start, &state) {
+                    state.must_quit.store(true, Ordering::Relaxed);
+                    panic!("{}", e);
+                }
+            }
+        }));
+    }
+    if config.console {
+        // blocked on the standard input, so never joined
+        thread::spawn({
+            let state = Arc::clone(&state);
+            move || {
+                if let Err(e) = control::console(state) {
+                    eprintln!("{:?}", e);
+                }
+            }
+        });
+    }
+    if let Some(path) = config.snapshot.clone() {
+        servers.push(thread::spawn({
+            let state = Arc::clone(&state);
+            move || {
+                if let Err(e) = snapshot::run_snapshots(
+                    &path,
+                    config.snapshot_interval_ms,
+                    &state,
+                ) {
+                    state.must_quit.store(true, Ordering::Relaxed);
+                    panic!("{}", e);
+                }
+            }
+        }));
+    }
+    let plc_result = run_plc(&state, &mut programs, &restored);
     state.must_quit.store(true, Ordering::Relaxed);
-    let server_result = th.join();
-    match server_result {
-        Ok(()) => plc_result,
-        Err(s_err) => match plc_result {
-            Ok(()) => Err(format!("{:?}", s_err))?,
-            Err(p_err) => Err(format!("{:?}\n{:?}", s_err, p_err))?,
-        },
+    let mut errors: Vec<_> = servers
+        .into_iter()
+        .filter_map(|server| server.join().err())
+        .map(|s_err| format!("{:?}", s_err))
+        .collect();
+    if let Err(p_err) = plc_result {
+        errors.push(format!("{:?}", p_err));
+    }
+    if let Some(path) = &config.snapshot {
+        match snapshot::save(path, &state) {
+            Ok(()) => println!("storage saved to '{}'", path),
+            Err(e) => errors.push(format!("{:?}", e)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// State of a server hosting `slaves`, configured with the defaults.
+    pub fn shared_state(
+        slaves: Vec<(u8, ModbusSimu)>,
+        clock: PlcClock,
+    ) -> SharedState {
+        let config = Config::default();
+        SharedState {
+            main_unit_id: slaves[0].0,
+            slaves: slaves
+                .into_iter()
+                .map(|(unit_id, storage)| (unit_id, RwLock::new(storage)))
+                .collect(),
+            forces: Forces::default(),
+            access: config.access,
+            identity: config.identity,
+            capture: None,
+            faults: FaultPlan::new(&config.faults, 0).unwrap(),
+            clock,
+            stats: Stats::default(),
+            clients: AtomicUsize::new(0),
+            must_quit: AtomicBool::new(false),
+        }
+    }
+
+    /// Buffer holding a frame with `header`, read by `recv_request_bytes`.
+    fn buffer_with(header: [u8; 7], body: &[u8]) -> [u8; MAX_TCP_ADU_LEN] {
+        let mut buffer = [0; MAX_TCP_ADU_LEN];
+        buffer[..7].copy_from_slice(&header);
+        buffer[7..7 + body.len()].copy_from_slice(body);
+        buffer
+    }
+
+    /// Server hosting the units 1 and 4.
+    fn two_slaves() -> SharedState {
+        let slave = || ModbusSimu::new(20, 0, 1, 5);
+        shared_state(
+            vec![(1, slave()), (4, slave())],
+            PlcClock::new(false, 100),
+        )
+    }
+
+    #[test]
+    fn classify_request() {
+        let state = two_slaves();
+        let body = [3, 0, 0, 0, 1];
+        let buffer = buffer_with([0, 9, 0, 0, 0, 6, 1], &body);
+        let Received::Request(request) =
+            classify_frame(&buffer, 5, &[], &state)
+        else {
+            panic!("request not recognized");
+        };
+        assert_eq!(request, &buffer[..12]);
+    }
+
+    #[test]
+    fn classify_skipped() {
+        let state = two_slaves();
+        // another protocol
+        let buffer = buffer_with([0, 9, 0, 1, 0, 6, 1], &[3, 0, 0, 0, 1]);
+        let Received::Skipped(frame) = classify_frame(&buffer, 5, &[], &state)
+        else {
+            panic!("other protocol not skipped");
+        };
+        assert_eq!(frame, &buffer[..12]);
+        // no function code
+        let buffer = buffer_with([0, 9, 0, 0, 0, 1, 1], &[]);
+        let Received::Skipped(frame) = classify_frame(&buffer, 0, &[], &state)
+        else {
+            panic!("empty frame not skipped");
+        };
+        assert_eq!(frame, &buffer[..7]);
+    }
+
+    /// Header and drained body of a 300 bytes write to `unit_id`.
+    fn oversized(unit_id: u8) -> ([u8; MAX_TCP_ADU_LEN], Vec<u8>) {
+        let buffer = buffer_with([0, 9, 0, 0, 0x01, 0x2d, unit_id], &[]);
+        let mut drained = vec![0; 300];
+        drained[0] = 16;
+        (buffer, drained)
+    }
+
+    #[test]
+    fn classify_oversized() {
+        let state = two_slaves();
+        let (buffer, drained) = oversized(4);
+        let Received::Rejected(request, response) =
+            classify_frame(&buffer, 300, &drained, &state)
+        else {
+            panic!("oversized frame not rejected");
+        };
+        assert_eq!(request, [&buffer[..7], &drained].concat());
+        assert_eq!(response, [0, 9, 0, 0, 0, 3, 4, 0x90, 3]);
+    }
+
+    #[test]
+    fn classify_oversized_not_answered() {
+        let state = two_slaves();
+        // broadcasts, and a unit this server does not host
+        for unit_id in [0, 255, 2] {
+            let (buffer, drained) = oversized(unit_id);
+            let Received::Skipped(frame) =
+                classify_frame(&buffer, 300, &drained, &state)
+            else {
+                panic!("oversized frame to {unit_id} answered");
+            };
+            assert_eq!(frame, [&buffer[..7], &drained].concat());
+        }
+    }
+
+    #[test]
+    fn requests_for_other_units_ignored() {
+        let state = two_slaves();
+        let request = |unit_id| {
+            let frame = [0, 1, 0, 0, 0, 6, unit_id, 3, 0, 0, 0, 1];
+            let proto = ModbusProto::TcpUdp;
+            process_request(&state, &frame, proto, Permission::ReadWrite, None)
+                .unwrap()
+        };
+        assert!(request(9).is_none());
+        assert!(request(0).is_none());
+        assert!(request(4).is_some());
+        let metrics = state.stats.prometheus();
+        assert!(metrics.contains("modbus_ignored_requests_total 1\n"));
+        // the broadcast and the request to unit 4
+        assert!(metrics.contains("modbus_requests_total{function=\"3\"} 2\n"));
     }
 }
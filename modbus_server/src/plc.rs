@@ -0,0 +1,117 @@
+mod chaser;
+mod counters;
+mod flicker;
+mod replay;
+mod tank;
+mod traffic_light;
+
+use std::error::Error;
+
+use crate::{config::Config, storage::ModbusSimu};
+
+/// When a scan runs, in simulated time, which follows the PLC period even
+/// when the operator changes it.
+#[derive(Debug, Clone, Copy)]
+pub struct ScanTime {
+    /// number of the scan, from 0
+    pub tick: u64,
+    /// simulated milliseconds from the first scan to this one
+    pub elapsed_ms: u64,
+    /// simulated milliseconds covered by this scan, the PLC period
+    pub period_ms: u64,
+}
+
+/// Behaviour executed by the simulated PLC.
+///
+/// `init` is called once before the first scan, with `restored` set when
+/// the storage was loaded from a snapshot, then `scan` is called once per
+/// PLC period with its time, while the storage is locked for writing.
+pub trait PlcProgram: Send {
+    fn init(
+        &mut self,
+        _storage: &mut ModbusSimu,
+        _restored: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn scan(
+        &mut self,
+        time: ScanTime,
+        storage: &mut ModbusSimu,
+    ) -> Result<(), Box<dyn Error>>;
+}
+
+pub struct ProgramEntry {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// input registers the program writes, which no other block may use
+    pub inputs: &'static [u16],
+    create: fn(&Config) -> Box<dyn PlcProgram>,
+}
+
+pub const PROGRAMS: &[ProgramEntry] = &[
+    ProgramEntry {
+        name: "chaser",
+        description: "bouncing light and bar graph on coils, \
+                      counters on holdings",
+        inputs: &[],
+        create: |_| Box::new(chaser::Chaser),
+    },
+    ProgramEntry {
+        name: "traffic_light",
+        description: "two-way crossroad lights on coils 0..6, \
+                      phase and remaining ticks on holdings 0..2",
+        inputs: &[],
+        create: |_| Box::new(traffic_light::TrafficLight),
+    },
+    ProgramEntry {
+        name: "counters",
+        description: "binary counter on coils, sawtooth timers with \
+                      growing presets on holdings",
+        inputs: &[],
+        create: |_| Box::new(counters::Counters),
+    },
+    ProgramEntry {
+        name: "flicker",
+        description: "seeded random toggling of coils and holdings",
+        inputs: &[],
+        create: |config| Box::new(flicker::Flicker::new(config.seed)),
+    },
+    ProgramEntry {
+        name: "tank",
+        description: "water tank level driven by the inflow valve (coil 0) \
+                      and outflow pump (coil 1) written by clients",
+        inputs: &[tank::LEVEL as u16],
+        create: |_| Box::<tank::Tank>::default(),
+    },
+    ProgramEntry {
+        name: "replay",
+        description: "re-applies the events recorded by modbus_client \
+                      in a plc.db file",
+        inputs: &[],
+        create: |config| {
+            Box::new(replay::Replay::new(
+                config.replay_db.clone(),
+                config.replay_device.clone(),
+                config.replay_speed,
+            ))
+        },
+    },
+];
+
+pub fn program_by_name(
+    name: &str,
+    config: &Config,
+) -> Result<Box<dyn PlcProgram>, Box<dyn Error>> {
+    match PROGRAMS.iter().find(|p| p.name == name) {
+        Some(entry) => Ok((entry.create)(config)),
+        None => {
+            let names: Vec<_> = PROGRAMS.iter().map(|p| p.name).collect();
+            Err(format!(
+                "unknown PLC program {name:?} (expected one of: {})",
+                names.join(", ")
+            ))?
+        }
+    }
+}
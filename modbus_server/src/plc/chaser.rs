@@ -0,0 +1,40 @@
+use std::error::Error;
+
+use super::{PlcProgram, ScanTime};
+use crate::storage::ModbusSimu;
+
+/// Light bouncing over the first third of the coils, bar graph growing and
+/// shrinking over the rest, and holding `i` counting every `i + 1` ticks.
+pub struct Chaser;
+
+impl PlcProgram for Chaser {
+    fn scan(
+        &mut self,
+        time: ScanTime,
+        storage: &mut ModbusSimu,
+    ) -> Result<(), Box<dyn Error>> {
+        let counter = time.tick as usize;
+        let coil_count = storage.coils.len();
+        let low = coil_count / 3;
+        let high = coil_count - low;
+        let span = (2 * low.saturating_sub(1)).max(1);
+        let mut current = counter % span;
+        if current >= low {
+            current = span - current;
+        }
+        for (i, c) in storage.coils[..low].iter_mut().enumerate() {
+            *c = i == current;
+        }
+        let mut current = counter % (2 * high).max(1);
+        if current > high {
+            current = 2 * high - current;
+        }
+        for (i, c) in storage.coils[low..].iter_mut().enumerate() {
+            *c = i < current;
+        }
+        for (i, h) in storage.holdings.iter_mut().enumerate() {
+            *h = (counter / (i + 1)) as u16;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,27 @@
+use std::error::Error;
+
+use super::{PlcProgram, ScanTime};
+use crate::storage::ModbusSimu;
+
+/// Counter/timer bank.
+///
+/// Coil `i` is bit `i` of the tick counter (coil 0 toggles every tick,
+/// coil 1 every two ticks...), holding `i` is a timer counting ticks and
+/// restarting from 0 when it reaches its preset `10 * (i + 1)`.
+pub struct Counters;
+
+impl PlcProgram for Counters {
+    fn scan(
+        &mut self,
+        time: ScanTime,
+        storage: &mut ModbusSimu,
+    ) -> Result<(), Box<dyn Error>> {
+        for (i, c) in storage.coils.iter_mut().enumerate() {
+            *c = i < 64 && (time.tick >> i) & 1 == 1;
+        }
+        for (i, h) in storage.holdings.iter_mut().enumerate() {
+            *h = (time.tick % (10 * (i as u64 + 1))) as u16;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use std::error::Error;
+
+use super::{PlcProgram, ScanTime};
+use crate::{rng::Rng, storage::ModbusSimu};
+
+/// Probability for each coil to toggle during a scan.
+const COIL_TOGGLE: f64 = 0.1;
+/// Probability for each holding to take a new random value during a scan.
+const HOLDING_CHANGE: f64 = 0.05;
+
+/// Random flicker, reproducible from the configured seed.
+pub struct Flicker {
+    rng: Rng,
+}
+
+impl Flicker {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl PlcProgram for Flicker {
+    fn scan(
+        &mut self,
+        _time: ScanTime,
+        storage: &mut ModbusSimu,
+    ) -> Result<(), Box<dyn Error>> {
+        for c in storage.coils.iter_mut() {
+            if self.rng.chance(COIL_TOGGLE) {
+                *c = !*c;
+            }
+        }
+        for h in storage.holdings.iter_mut() {
+            if self.rng.chance(HOLDING_CHANGE) {
+                *h = self.rng.next_u64() as u16;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use std::error::Error;
+
+use super::{PlcProgram, ScanTime};
+use crate::storage::{Address, ModbusSimu};
+
+struct ReplayEvent {
+    /// time since the first recorded event, in milliseconds
+    offset_ms: u64,
+    target: Address,
+    state: u16,
+}
+
+/// Replays the `event` table recorded by `modbus_client`.
+///
+/// Events are applied with their original spacing, divided by `speed`;
+/// once the last one is applied the storage is left untouched. Both the
+/// original layout (schema version 0, text addresses like `%MW3`) and the
+/// current one (version 1, `area` and numeric `address`) are read. When
+/// several devices were recorded, `device` selects the one to replay.
+pub struct Replay {
+    db_path: Option<String>,
+    device: Option<String>,
+    speed: f64,
+    events: Vec<ReplayEvent>,
+    next: usize,
+}
+
+/// Schema version of the databases written by `modbus_client`.
+const SCHEMA_VERSION: u32 = 1;
+
+/// Storage address of an event of the current schema.
+fn area_address(area: &str, address: Option<u16>) -> Option<Address> {
+    let address = address?;
+    match area {
+        "coil" => Some(Address::Coil(address)),
+        "discrete_input" => Some(Address::Discrete(address)),
+        "input_register" => Some(Address::Input(address)),
+        "holding_register" => Some(Address::Holding(address)),
+        _ => None,
+    }
+}
+
+impl Replay {
+    pub fn new(
+        db_path: Option<String>,
+        device: Option<String>,
+        speed: f64,
+    ) -> Self {
+        Self {
+            db_path,
+            device,
+            speed,
+            events: Vec::new(),
+            next: 0,
+        }
+    }
+
+    /// Devices found in the recording; none for recordings of a single
+    /// device made before the `device` column existed.
+    fn devices(
+        db: &rusqlite::Connection,
+    ) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+        let has_device: bool = db.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('event') \
+             WHERE name = 'device'",
+            (),
+            |row| row.get(0),
+        )?;
+        if !has_device {
+            return Ok(None);
+        }
+        let devices = db
+            .prepare(
+                "SELECT DISTINCT device FROM event \
+                 WHERE device IS NOT NULL ORDER BY device",
+            )?
+            .query_map((), |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(Some(devices))
+    }
+
+    fn load(&mut self, db_path: &str) -> Result<(), Box<dyn Error>> {
+        let db = rusqlite::Connection::open_with_flags(
+            db_path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )?;
+        let version: u32 =
+            db.query_row("PRAGMA user_version", (), |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            Err(format!(
+                "'{db_path}' has schema version {version}, only versions \
+                 up to {SCHEMA_VERSION} can be replayed"
+            ))?
+        }
+        let devices = Self::devices(&db)?;
+        let device = match (&self.device, &devices) {
+            (None, None) => None,
+            (None, Some(devices)) if devices.len() <= 1 => None,
+            (None, Some(devices)) => Err(format!(
+                "'{db_path}' holds the events of several devices \
+                 {devices:?}, choose one with --replay-device"
+            ))?,
+            (Some(device), Some(devices)) if devices.contains(device) => {
+                Some(device)
+            }
+            (Some(device), _) => Err(format!(
+                "'{db_path}' holds no events of device {device:?}"
+            ))?,
+        };
+        let filter = if device.is_some() { "device = ?1" } else { "1" };
+        let mut query = db.prepare(&if version == 0 {
+            format!(
+                "SELECT utc_ms, NULL, address, state FROM event \
+                 WHERE {filter} ORDER BY utc_ms, id"
+            )
+        } else {
+            format!(
+                "SELECT utc_ms, area, address, value FROM event \
+                 WHERE area != 'connection' AND {filter} \
+                 ORDER BY utc_ms, id"
+            )
+        })?;
+        let mut rows = match device {
+            Some(device) => query.query([device])?,
+            None => query.query(())?,
+        };
+        let mut skipped = 0;
+        let mut first_utc_ms = None;
+        while let Some(row) = rows.next()? {
+            let utc_ms: u64 = row.get(0)?;
+            let target = if version == 0 {
+                row.get::<_, String>(2)?.parse().ok()
+            } else {
+                area_address(&row.get::<_, String>(1)?, row.get(2)?)
+            };
+            let Some(target) = target else {
+                skipped += 1;
+                continue;
+            };
+            let first_utc_ms = *first_utc_ms.get_or_insert(utc_ms);
+            self.events.push(ReplayEvent {
+                offset_ms: utc_ms - first_utc_ms,
+                target,
+                state: row.get(3)?,
+            });
+        }
+        if skipped > 0 {
+            eprintln!("replay: skipped {skipped} events with unknown address");
+        }
+        Ok(())
+    }
+}
+
+impl PlcProgram for Replay {
+    fn init(
+        &mut self,
+        storage: &mut ModbusSimu,
+        _restored: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let db_path = self
+            .db_path
+            .clone()
+            .ok_or("replay needs a recorded database (--replay FILE)")?;
+        self.load(&db_path)?;
+        let (mut coils, mut discretes, mut inputs, mut holdings) =
+            (0, 0, 0, 0);
+        for event in &self.events {
+            let (count, i) = match event.target {
+                Address::Coil(i) => (&mut coils, i),
+                Address::Discrete(i) => (&mut discretes, i),
+                Address::Input(i) => (&mut inputs, i),
+                Address::Holding(i) => (&mut holdings, i),
+            };
+            *count = (*count).max(i as usize + 1);
+        }
+        if coils > storage.coils.len()
+            || discretes > storage.discretes.len()
+            || inputs > storage.inputs.len()
+            || holdings > storage.holdings.len()
+        {
+            Err(format!(
+                "the recording needs at least {coils} coils, {discretes} \
+                 discretes, {inputs} inputs and {holdings} holdings"
+            ))?
+        }
+        println!("replaying {} events from '{}'", self.events.len(), db_path);
+        Ok(())
+    }
+
+    fn scan(
+        &mut self,
+        time: ScanTime,
+        storage: &mut ModbusSimu,
+    ) -> Result<(), Box<dyn Error>> {
+        let elapsed_ms = time.elapsed_ms as f64 * self.speed;
+        while let Some(event) = self.events.get(self.next) {
+            if event.offset_ms as f64 > elapsed_ms {
+                break;
+            }
+            storage.set(event.target, event.state)?;
+            self.next += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use std::error::Error;
+
+use super::{PlcProgram, ScanTime};
+use crate::storage::ModbusSimu;
+
+/// Tank volume in litres.
+const CAPACITY: f64 = 1000.0;
+/// Flow through the open inflow valve, in litres per second.
+const INFLOW: f64 = 20.0;
+/// Flow extracted by the running outflow pump, in litres per second.
+const PUMP_OUTFLOW: f64 = 15.0;
+/// Constant consumption drawn from the tank, in litres per second.
+const DEMAND: f64 = 5.0;
+
+const INFLOW_VALVE: usize = 0;
+const OUTFLOW_PUMP: usize = 1;
+const LOW_SWITCH: usize = 0;
+const HIGH_SWITCH: usize = 1;
+pub(super) const LEVEL: usize = 0;
+const SETPOINT: usize = 4;
+
+/// Water tank whose actuators are driven by the Modbus clients.
+///
+/// The program never writes coils nor holdings, so what clients write there
+/// stays in place and acts on the simulated physics:
+/// - coil 0: inflow valve, coil 1: outflow pump (written by clients);
+/// - input 0: level in tenths of percent (0..=1000);
+/// - discrete 0: low level switch (< 10 %), discrete 1: high level switch
+///   (> 90 %), when the discrete inputs exist;
+/// - holding 4: level setpoint in tenths of percent, initialised to 500
+///   unless restored from a snapshot, then left to the clients (holdings
+///   0..4 still advertise the area sizes).
+///
+/// A restored level becomes the initial volume, the tank is half full
+/// otherwise.
+pub struct Tank {
+    /// current volume, in litres
+    volume: f64,
+}
+
+impl Default for Tank {
+    fn default() -> Self {
+        Self {
+            volume: CAPACITY / 2.0,
+        }
+    }
+}
+
+impl PlcProgram for Tank {
+    fn init(
+        &mut self,
+        storage: &mut ModbusSimu,
+        restored: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if storage.coils.get(OUTFLOW_PUMP).is_none()
+            || storage.inputs.get(LEVEL).is_none()
+            || storage.holdings.get(SETPOINT).is_none()
+        {
+            Err("tank needs at least 2 coils, 1 input and 5 holdings \
+                 (e.g. --inputs 1 --discretes 2)")?
+        }
+        if restored {
+            let level = storage.inputs[LEVEL].min(1000);
+            self.volume = CAPACITY * level as f64 / 1000.0;
+        } else {
+            storage.holdings[SETPOINT] = 500;
+        }
+        Ok(())
+    }
+
+    fn scan(
+        &mut self,
+        time: ScanTime,
+        storage: &mut ModbusSimu,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut flow = -DEMAND;
+        if storage.coils[INFLOW_VALVE] {
+            flow += INFLOW;
+        }
+        if storage.coils[OUTFLOW_PUMP] {
+            flow -= PUMP_OUTFLOW;
+        }
+        let dt = time.period_ms as f64 / 1000.0;
+        self.volume = (self.volume + flow * dt).clamp(0.0, CAPACITY);
+        let level = (1000.0 * self.volume / CAPACITY).round() as u16;
+        storage.inputs[LEVEL] = level;
+        if let Some(low) = storage.discretes.get_mut(LOW_SWITCH) {
+            *low = level < 100;
+        }
+        if let Some(high) = storage.discretes.get_mut(HIGH_SWITCH) {
+            *high = level > 900;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use std::error::Error;
+
+use super::{PlcProgram, ScanTime};
+use crate::storage::ModbusSimu;
+
+const RED: usize = 0;
+const YELLOW: usize = 1;
+const GREEN: usize = 2;
+
+/// (north-south light, east-west light, duration in ticks) of each phase
+const PHASES: [(usize, usize, u64); 6] = [
+    (GREEN, RED, 40),
+    (YELLOW, RED, 10),
+    (RED, RED, 5),
+    (RED, GREEN, 40),
+    (RED, YELLOW, 10),
+    (RED, RED, 5),
+];
+
+/// Crossroad traffic lights.
+///
+/// Coils 0..3 are the red/yellow/green lights of the north-south road,
+/// coils 3..6 those of the east-west road; holding 0 is the current phase
+/// and holding 1 the number of ticks left in it.
+pub struct TrafficLight;
+
+impl PlcProgram for TrafficLight {
+    fn init(
+        &mut self,
+        storage: &mut ModbusSimu,
+        _restored: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if storage.coils.len() < 6 || storage.holdings.len() < 2 {
+            Err("traffic_light needs at least 6 coils and 2 holdings")?
+        }
+        Ok(())
+    }
+
+    fn scan(
+        &mut self,
+        time: ScanTime,
+        storage: &mut ModbusSimu,
+    ) -> Result<(), Box<dyn Error>> {
+        let cycle: u64 = PHASES.iter().map(|p| p.2).sum();
+        let mut elapsed = time.tick % cycle;
+        let mut phase = 0;
+        while elapsed >= PHASES[phase].2 {
+            elapsed -= PHASES[phase].2;
+            phase += 1;
+        }
+        let (north_south, east_west, duration) = PHASES[phase];
+        for (i, c) in storage.coils[..3].iter_mut().enumerate() {
+            *c = i == north_south;
+        }
+        for (i, c) in storage.coils[3..6].iter_mut().enumerate() {
+            *c = i == east_west;
+        }
+        storage.holdings[0] = phase as u16;
+        storage.holdings[1] = (duration - elapsed) as u16;
+        Ok(())
+    }
+}
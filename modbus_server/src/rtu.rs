@@ -0,0 +1,97 @@
+use std::{
+    error::Error,
+    io::{ErrorKind, Read, Write},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use rmodbus::ModbusProto;
+
+use crate::{
+    access::Permission,
+    config::Parity,
+    faults::{Outcome, Transport},
+    SharedState,
+};
+
+/// Silent interval (3.5 character times) which delimits RTU frames.
+///
+/// Above 19200 bauds the Modbus serial line specification recommends a
+/// fixed 1.75 ms instead of the computed value.
+fn frame_silence(baud_rate: u32) -> Duration {
+    if baud_rate > 19200 {
+        Duration::from_micros(1750)
+    } else {
+        // one character is 11 bits on the line (start, 8 data, parity or
+        // second stop bit, stop)
+        Duration::from_micros(3_500_000 * 11 / baud_rate as u64)
+    }
+}
+
+pub fn modbus_rtu_server(
+    device: &str,
+    baud_rate: u32,
+    parity: Parity,
+    state: Arc<SharedState>,
+) -> Result<(), Box<dyn Error>> {
+    let silence = frame_silence(baud_rate);
+    let (parity, stop_bits) = match parity {
+        Parity::None => (serialport::Parity::None, serialport::StopBits::Two),
+        Parity::Even => (serialport::Parity::Even, serialport::StopBits::One),
+        Parity::Odd => (serialport::Parity::Odd, serialport::StopBits::One),
+    };
+    let mut port = serialport::new(device, baud_rate)
+        .parity(parity)
+        .stop_bits(stop_bits)
+        .timeout(silence)
+        .open()
+        .map_err(|e| format!("cannot open serial device {device:?}: {e}"))?;
+    println!(
+        "modbus rtu server listening on '{}' at {} bauds",
+        device, baud_rate
+    );
+    let mut faults = state.faults.session(Transport::Rtu, 0);
+    let mut frame = Vec::new();
+    let mut last_byte = Instant::now();
+    let mut buffer = [0; 256];
+    while !state.must_quit.load(Ordering::Relaxed) {
+        match port.read(&mut buffer) {
+            Ok(count) => {
+                if last_byte.elapsed() >= silence {
+                    // whatever was pending belongs to an interrupted frame
+                    frame.clear();
+                }
+                frame.extend_from_slice(&buffer[..count]);
+                last_byte = Instant::now();
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => Err(e)?,
+        }
+        if frame.is_empty() || last_byte.elapsed() < silence {
+            continue;
+        }
+        // the line stayed silent long enough: the frame is complete
+        state.stats.client_request(device);
+        if frame.len() > buffer.len() {
+            state.stats.invalid_frame();
+            eprintln!(
+                "discarding oversized rtu frame ({} bytes)",
+                frame.len()
+            );
+        } else {
+            // serial line clients cannot be told apart
+            let permission = Permission::ReadWrite;
+            match faults.serve(&state, &frame, ModbusProto::Rtu, permission) {
+                Ok(Outcome::Respond(response)) => port.write_all(&response)?,
+                // there is no connection to reset
+                Ok(Outcome::Silent | Outcome::Reset) => {}
+                Err(e) => {
+                    eprintln!("invalid rtu frame {:02x?}: {:?}", frame, e)
+                }
+            }
+        }
+        frame.clear();
+    }
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use std::{error::Error, f64::consts::TAU, str::FromStr};
+
+use serde::Deserialize;
+
+use crate::{
+    plc::{PlcProgram, ScanTime},
+    rng::Rng,
+    storage::{Address, ModbusSimu},
+};
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignalKind {
+    Sine,
+    Ramp,
+    Square,
+    RandomWalk,
+    Noise,
+    Step,
+}
+
+impl FromStr for SignalKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sine" => Ok(SignalKind::Sine),
+            "ramp" => Ok(SignalKind::Ramp),
+            "square" => Ok(SignalKind::Square),
+            "random_walk" => Ok(SignalKind::RandomWalk),
+            "noise" => Ok(SignalKind::Noise),
+            "step" => Ok(SignalKind::Step),
+            _ => Err(format!("unknown signal kind {s:?}")),
+        }
+    }
+}
+
+/// Signal generator bound to an input register (`%IW<n>`) or a discrete
+/// input (`%I<n>`).
+///
+/// Every kind produces a shape between 0 and 1 over `period_ms`: registers
+/// receive `offset + amplitude * shape`, discrete inputs are on while the
+/// shape is at least 0.5.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignalConfig {
+    pub target: String,
+    pub kind: SignalKind,
+    #[serde(default = "default_period_ms")]
+    pub period_ms: u64,
+    #[serde(default = "default_amplitude")]
+    pub amplitude: f64,
+    #[serde(default)]
+    pub offset: f64,
+}
+
+fn default_period_ms() -> u64 {
+    10_000
+}
+
+fn default_amplitude() -> f64 {
+    1000.0
+}
+
+impl FromStr for SignalConfig {
+    type Err = String;
+
+    /// Parses `TARGET=KIND[:key=value,...]`, for instance
+    /// `%IW0=sine:period_ms=2000,amplitude=500,offset=100`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (target, rest) = s
+            .split_once('=')
+            .ok_or("expected TARGET=KIND[:key=value,...]")?;
+        let (kind, settings) = rest.split_once(':').unwrap_or((rest, ""));
+        let mut signal = SignalConfig {
+            target: target.to_owned(),
+            kind: kind.parse()?,
+            period_ms: default_period_ms(),
+            amplitude: default_amplitude(),
+            offset: 0.0,
+        };
+        for setting in settings.split(',').filter(|s| !s.is_empty()) {
+            let invalid = || format!("invalid signal setting {setting:?}");
+            let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+            match key {
+                "period_ms" => {
+                    signal.period_ms = value.parse().map_err(|_| invalid())?
+                }
+                "amplitude" => {
+                    signal.amplitude = value.parse().map_err(|_| invalid())?
+                }
+                "offset" => {
+                    signal.offset = value.parse().map_err(|_| invalid())?
+                }
+                _ => Err(invalid())?,
+            }
+        }
+        Ok(signal)
+    }
+}
+
+struct Signal {
+    config: SignalConfig,
+    target: Address,
+    rng: Rng,
+    /// current shape of the random walk
+    walk: f64,
+}
+
+impl Signal {
+    fn shape(&mut self, time: ScanTime) -> f64 {
+        let time_ms = time.elapsed_ms;
+        let period = self.config.period_ms as f64;
+        let phase = (time_ms % self.config.period_ms) as f64 / period;
+        match self.config.kind {
+            SignalKind::Sine => (1.0 + (TAU * phase).sin()) / 2.0,
+            SignalKind::Ramp => phase,
+            SignalKind::Square => f64::from(u8::from(phase < 0.5)),
+            SignalKind::RandomWalk => {
+                // crossing the whole range takes about one period
+                let step = 2.0 * time.period_ms as f64 / period;
+                let delta = (2.0 * self.rng.next_f64() - 1.0) * step;
+                self.walk = (self.walk + delta).clamp(0.0, 1.0);
+                self.walk
+            }
+            SignalKind::Noise => self.rng.next_f64(),
+            SignalKind::Step => {
+                f64::from(u8::from(time_ms >= self.config.period_ms))
+            }
+        }
+    }
+}
+
+/// Signal generators of one slave, run after its PLC program.
+pub struct SignalBank {
+    signals: Vec<Signal>,
+}
+
+impl SignalBank {
+    pub fn new(
+        configs: &[SignalConfig],
+        seed: u64,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut signals = Vec::new();
+        for (i, config) in configs.iter().enumerate() {
+            let target = match config.target.parse() {
+                Ok(target @ (Address::Discrete(_) | Address::Input(_))) => {
+                    target
+                }
+                _ => Err(format!(
+                    "invalid signal target {:?} (expected %IW<n> or %I<n>)",
+                    config.target
+                ))?,
+            };
+            if config.period_ms == 0 {
+                Err("the period of a signal must be at least 1 ms")?
+            }
+            signals.push(Signal {
+                config: config.clone(),
+                target,
+                rng: Rng::new(seed.wrapping_add(i as u64)),
+                walk: 0.5,
+            });
+        }
+        Ok(Self { signals })
+    }
+}
+
+impl PlcProgram for SignalBank {
+    fn init(
+        &mut self,
+        storage: &mut ModbusSimu,
+        _restored: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        for signal in &self.signals {
+            if storage.get(signal.target).is_err() {
+                Err(format!(
+                    "signal target {} is out of the configured storage",
+                    signal.config.target
+                ))?
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(
+        &mut self,
+        time: ScanTime,
+        storage: &mut ModbusSimu,
+    ) -> Result<(), Box<dyn Error>> {
+        for signal in &mut self.signals {
+            let shape = signal.shape(time);
+            let value = match signal.target {
+                Address::Discrete(_) => u16::from(shape >= 0.5),
+                _ => {
+                    let value =
+                        signal.config.offset + signal.config.amplitude * shape;
+                    // saturating conversion into the register range
+                    value.round() as u16
+                }
+            };
+            storage.set(signal.target, value)?;
+        }
+        Ok(())
+    }
+}
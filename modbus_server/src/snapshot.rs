@@ -0,0 +1,149 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    path::Path,
+    sync::atomic::Ordering,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{storage::ModbusSimu, SharedState};
+
+/// Image of the storage of every slave, saved as TOML.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    slaves: Vec<SlaveSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SlaveSnapshot {
+    unit_id: u8,
+    #[serde(flatten)]
+    context: ModbusSimu,
+}
+
+pub fn save(path: &str, state: &SharedState) -> Result<(), Box<dyn Error>> {
+    let slaves = state
+        .slaves
+        .iter()
+        .map(|(unit_id, context)| SlaveSnapshot {
+            unit_id: *unit_id,
+            context: context.read().unwrap().clone(),
+        })
+        .collect();
+    let text = toml::to_string(&Snapshot { slaves })?;
+    // write then rename so that a crash never leaves a partial snapshot
+    let temporary = format!("{path}.tmp");
+    std::fs::write(&temporary, text)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Loads a snapshot into the slaves, if the file exists.
+///
+/// Areas are restored up to the size configured for this run; unknown unit
+/// ids and extra values are reported and ignored. The first holding
+/// registers keep advertising the configured sizes. Returns the unit ids of
+/// the restored slaves.
+pub fn restore(
+    path: &str,
+    state: &SharedState,
+) -> Result<BTreeSet<u8>, Box<dyn Error>> {
+    let mut restored = BTreeSet::new();
+    if !Path::new(path).exists() {
+        println!("no snapshot to restore from '{}'", path);
+        return Ok(restored);
+    }
+    let text = std::fs::read_to_string(path)?;
+    let snapshot: Snapshot = toml::from_str(&text)
+        .map_err(|e| format!("invalid snapshot {path:?}: {e}"))?;
+    for slave in snapshot.slaves {
+        let Some(context) = state.slaves.get(&slave.unit_id) else {
+            eprintln!("snapshot: ignoring unknown unit id {}", slave.unit_id);
+            continue;
+        };
+        restored.insert(slave.unit_id);
+        let mut context = context.write().unwrap();
+        let saved = slave.context;
+        let truncated = restore_area(&mut context.coils, &saved.coils)
+            | restore_area(&mut context.discretes, &saved.discretes)
+            | restore_area(&mut context.inputs, &saved.inputs)
+            | restore_area(&mut context.holdings, &saved.holdings);
+        // the sizes of this run, not the saved ones
+        context.advertise_sizes();
+        if truncated {
+            eprintln!(
+                "snapshot: unit id {} has more values than configured, \
+                 extra values ignored",
+                slave.unit_id
+            );
+        }
+    }
+    println!("storage restored from '{}'", path);
+    Ok(restored)
+}
+
+/// Copies the saved values which fit, returns whether some did not.
+fn restore_area<T: Copy>(area: &mut [T], saved: &[T]) -> bool {
+    let count = area.len().min(saved.len());
+    area[..count].copy_from_slice(&saved[..count]);
+    saved.len() > count
+}
+
+/// Saves the storage every `interval_ms` until `must_quit` is set.
+pub fn run_snapshots(
+    path: &str,
+    interval_ms: u64,
+    state: &SharedState,
+) -> Result<(), Box<dyn Error>> {
+    let interval = Duration::from_millis(interval_ms);
+    let mut last_save = Instant::now();
+    while !state.must_quit.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(100).min(interval));
+        if last_save.elapsed() >= interval {
+            save(path, state)?;
+            last_save = Instant::now();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::PlcClock, tests::shared_state};
+
+    #[test]
+    fn restore_other_sizes() {
+        let path = std::env::temp_dir().join(format!(
+            "modbus_server_snapshot_{}.toml",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        let mut saved = ModbusSimu::new(20, 0, 1, 5);
+        saved.advertise_sizes();
+        saved.coils[3] = true;
+        saved.inputs[0] = 42;
+        saved.holdings[4] = 1234;
+        save(
+            path,
+            &shared_state(vec![(1, saved)], PlcClock::new(false, 100)),
+        )
+        .unwrap();
+
+        let mut context = ModbusSimu::new(40, 4, 4, 8);
+        context.advertise_sizes();
+        let state =
+            shared_state(vec![(1, context)], PlcClock::new(false, 100));
+        let restored = restore(path, &state);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(restored.unwrap(), BTreeSet::from([1]));
+        let context = state.slaves[&1].read().unwrap();
+        assert_eq!(context.holdings, [40, 4, 4, 8, 1234, 0, 0, 0]);
+        assert_eq!(context.inputs, [42, 0, 0, 0]);
+        assert!(context.coils[3]);
+        assert_eq!(context.coils.iter().filter(|coil| **coil).count(), 1);
+    }
+}
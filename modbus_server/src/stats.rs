@@ -0,0 +1,496 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::Write as _,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::SharedState;
+
+/// Period over which the request rate of each client is measured.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Most clients counted separately; the requests of the others, which
+/// may be spoofed UDP sources, are counted together under `OTHER_CLIENTS`.
+const MAX_CLIENTS: usize = 1000;
+const OTHER_CLIENTS: &str = "other";
+
+/// How often the register block is refreshed.
+const REFRESH: Duration = Duration::from_millis(200);
+
+/// Functions with a request counter in the register block, in order.
+const REGISTER_FUNCTIONS: [u8; 11] = [1, 2, 3, 4, 5, 6, 15, 16, 22, 23, 43];
+
+/// Number of input registers of the statistics block.
+pub const STATS_REGISTERS: u16 = 20 + 2 * REGISTER_FUNCTIONS.len() as u16;
+
+#[derive(Default)]
+struct FunctionStats {
+    requests: u64,
+    /// requests answered with an exception
+    exceptions: u64,
+}
+
+struct ClientStats {
+    requests: u64,
+    window_start: Instant,
+    window_requests: u64,
+    /// requests of the window before the current one
+    previous_requests: u64,
+}
+
+impl ClientStats {
+    fn new(now: Instant) -> Self {
+        Self {
+            requests: 0,
+            window_start: now,
+            window_requests: 0,
+            previous_requests: 0,
+        }
+    }
+
+    /// Starts a new window once the current one is over.
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now - self.window_start;
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        self.previous_requests = if elapsed < 2 * RATE_WINDOW {
+            self.window_requests
+        } else {
+            0
+        };
+        self.window_requests = 0;
+        let windows = elapsed.as_nanos() / RATE_WINDOW.as_nanos();
+        self.window_start += RATE_WINDOW * windows as u32;
+    }
+
+    /// Requests per second over the last `RATE_WINDOW`, assuming the
+    /// requests of the previous window were evenly spread.
+    fn rate(&mut self, now: Instant) -> f64 {
+        self.roll(now);
+        let window = RATE_WINDOW.as_secs_f64();
+        let progress = (now - self.window_start).as_secs_f64() / window;
+        let previous = self.previous_requests as f64 * (1.0 - progress);
+        (previous + self.window_requests as f64) / window
+    }
+}
+
+#[derive(Default)]
+struct ScanStats {
+    count: u64,
+    last: Duration,
+    min: Duration,
+    max: Duration,
+    total: Duration,
+}
+
+impl ScanStats {
+    fn mean(&self) -> Duration {
+        match u32::try_from(self.count) {
+            Ok(0) => Duration::ZERO,
+            Ok(count) => self.total / count,
+            Err(_) => self.total.div_f64(self.count as f64),
+        }
+    }
+}
+
+/// Activity of the simulator: requests by function, errors, request rate
+/// of each client and duration of the PLC scans.
+///
+/// The statistics cover every transport and every slave since the server
+/// started. They are exposed as a block of input registers of the main
+/// slave and as a Prometheus endpoint.
+#[derive(Default)]
+pub struct Stats {
+    functions: Mutex<BTreeMap<u8, FunctionStats>>,
+    /// by IP address, or by device for the serial line
+    clients: Mutex<BTreeMap<String, ClientStats>>,
+    /// frames which could not be processed at all
+    invalid_frames: AtomicU64,
+    /// requests for units this server does not host
+    ignored_requests: AtomicU64,
+    /// TCP connections ended by an error or a timeout
+    connection_errors: AtomicU64,
+    scans: Mutex<ScanStats>,
+}
+
+impl Stats {
+    /// Records a processed request and whether it was answered with an
+    /// exception.
+    pub fn request(&self, func: u8, exception: bool) {
+        let mut functions = self.functions.lock().unwrap();
+        let stats = functions.entry(func).or_default();
+        stats.requests += 1;
+        stats.exceptions += u64::from(exception);
+    }
+
+    /// Records a request received from a client, valid or not.
+    pub fn client_request(&self, client: &str) {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let client =
+            if clients.contains_key(client) || clients.len() < MAX_CLIENTS {
+                client
+            } else {
+                OTHER_CLIENTS
+            };
+        let stats = match clients.get_mut(client) {
+            Some(stats) => stats,
+            None => clients
+                .entry(client.to_owned())
+                .or_insert_with(|| ClientStats::new(now)),
+        };
+        stats.roll(now);
+        stats.requests += 1;
+        stats.window_requests += 1;
+    }
+
+    pub fn invalid_frame(&self) {
+        self.invalid_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ignored_request(&self) {
+        self.ignored_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_error(&self) {
+        self.connection_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn scan_done(&self, duration: Duration) {
+        let mut scans = self.scans.lock().unwrap();
+        scans.min = if scans.count == 0 {
+            duration
+        } else {
+            scans.min.min(duration)
+        };
+        scans.max = scans.max.max(duration);
+        scans.last = duration;
+        scans.total += duration;
+        scans.count += 1;
+    }
+
+    /// Request rate of each client.
+    fn client_rates(&self) -> Vec<(String, u64, f64)> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        clients
+            .iter_mut()
+            .map(|(client, stats)| {
+                (client.clone(), stats.requests, stats.rate(now))
+            })
+            .collect()
+    }
+
+    /// Fills the register block, made of 32-bit values (high word first):
+    ///
+    /// | offset | value                                           |
+    /// |--------|-------------------------------------------------|
+    /// | 0      | requests                                        |
+    /// | 2      | exception responses                             |
+    /// | 4      | invalid frames                                  |
+    /// | 6      | connection errors                               |
+    /// | 8      | requests per second, all clients together       |
+    /// | 10     | PLC scans                                       |
+    /// | 12     | last PLC scan duration, in µs                   |
+    /// | 14     | shortest PLC scan duration, in µs               |
+    /// | 16     | longest PLC scan duration, in µs                |
+    /// | 18     | mean PLC scan duration, in µs                   |
+    /// | 20     | requests of functions 1, 2, 3, 4, 5, 6, 15, 16, |
+    /// |        | 22, 23 and 43, two registers each               |
+    ///
+    /// Values wrap around; the ignored requests and the rates of each
+    /// client are only available from the Prometheus endpoint.
+    fn fill_registers(&self, registers: &mut [u16]) {
+        let mut values = Vec::new();
+        {
+            let functions = self.functions.lock().unwrap();
+            let total = |get: fn(&FunctionStats) -> u64| {
+                functions.values().map(get).sum::<u64>()
+            };
+            values.push(total(|f| f.requests));
+            values.push(total(|f| f.exceptions));
+            values.push(self.invalid_frames.load(Ordering::Relaxed));
+            values.push(self.connection_errors.load(Ordering::Relaxed));
+            let rates = self.client_rates();
+            values.push(rates.iter().map(|c| c.2).sum::<f64>().round() as u64);
+            let scans = self.scans.lock().unwrap();
+            values.push(scans.count);
+            for duration in [scans.last, scans.min, scans.max, scans.mean()] {
+                values.push(duration.as_micros() as u64);
+            }
+            for func in REGISTER_FUNCTIONS {
+                values.push(functions.get(&func).map_or(0, |f| f.requests));
+            }
+        }
+        for (pair, value) in registers.chunks_mut(2).zip(values) {
+            let value = value as u32;
+            pair[0] = (value >> 16) as u16;
+            pair[1] = value as u16;
+        }
+    }
+
+    /// Statistics in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let mut text = String::new();
+        {
+            let functions = self.functions.lock().unwrap();
+            family(
+                &mut text,
+                "modbus_requests_total",
+                "counter",
+                "Requests processed, by function code.",
+            );
+            for (func, stats) in functions.iter() {
+                let _ = writeln!(
+                    text,
+                    "modbus_requests_total{{function=\"{func}\"}} {}",
+                    stats.requests
+                );
+            }
+            family(
+                &mut text,
+                "modbus_exceptions_total",
+                "counter",
+                "Requests answered with an exception, by function code.",
+            );
+            for (func, stats) in functions.iter() {
+                let _ = writeln!(
+                    text,
+                    "modbus_exceptions_total{{function=\"{func}\"}} {}",
+                    stats.exceptions
+                );
+            }
+        }
+        family(
+            &mut text,
+            "modbus_invalid_frames_total",
+            "counter",
+            "Frames which could not be processed.",
+        );
+        let _ = writeln!(
+            text,
+            "modbus_invalid_frames_total {}",
+            self.invalid_frames.load(Ordering::Relaxed)
+        );
+        family(
+            &mut text,
+            "modbus_ignored_requests_total",
+            "counter",
+            "Requests for units this server does not host.",
+        );
+        let _ = writeln!(
+            text,
+            "modbus_ignored_requests_total {}",
+            self.ignored_requests.load(Ordering::Relaxed)
+        );
+        family(
+            &mut text,
+            "modbus_connection_errors_total",
+            "counter",
+            "TCP connections ended by an error or a timeout.",
+        );
+        let _ = writeln!(
+            text,
+            "modbus_connection_errors_total {}",
+            self.connection_errors.load(Ordering::Relaxed)
+        );
+        let rates = self.client_rates();
+        family(
+            &mut text,
+            "modbus_client_requests_total",
+            "counter",
+            "Requests received, by client.",
+        );
+        for (client, requests, _) in &rates {
+            let _ = writeln!(
+                text,
+                "modbus_client_requests_total{{client=\"{}\"}} {requests}",
+                escape_label(client)
+            );
+        }
+        family(
+            &mut text,
+            "modbus_client_request_rate",
+            "gauge",
+            "Requests per second over the last 10 s, by client.",
+        );
+        for (client, _, rate) in &rates {
+            let _ = writeln!(
+                text,
+                "modbus_client_request_rate{{client=\"{}\"}} {rate:.3}",
+                escape_label(client)
+            );
+        }
+        let scans = self.scans.lock().unwrap();
+        family(
+            &mut text,
+            "plc_scan_duration_seconds",
+            "summary",
+            "Duration of the PLC scans.",
+        );
+        let _ = writeln!(
+            text,
+            "plc_scan_duration_seconds_sum {:.6}\n\
+             plc_scan_duration_seconds_count {}",
+            scans.total.as_secs_f64(),
+            scans.count
+        );
+        for (name, duration) in [
+            ("last", scans.last),
+            ("min", scans.min),
+            ("max", scans.max),
+            ("mean", scans.mean()),
+        ] {
+            let metric = format!("plc_scan_duration_{name}_seconds");
+            family(
+                &mut text,
+                &metric,
+                "gauge",
+                &format!("Duration of the PLC scans ({name})."),
+            );
+            let _ = writeln!(text, "{metric} {:.6}", duration.as_secs_f64());
+        }
+        text
+    }
+}
+
+/// Writes the description of a metric.
+fn family(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {name} {help}");
+    let _ = writeln!(text, "# TYPE {name} {kind}");
+}
+
+/// Label value as written in the exposition format, where backslashes,
+/// double quotes and line feeds are escaped.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Keeps the statistics block of the main slave up to date, from the
+/// input register `start` on.
+pub fn update_registers(
+    start: u16,
+    state: &SharedState,
+) -> Result<(), Box<dyn Error>> {
+    let start = usize::from(start);
+    let end = start + usize::from(STATS_REGISTERS);
+    while !state.must_quit.load(Ordering::Relaxed) {
+        let mut storage = state.slaves[&state.main_unit_id].write().unwrap();
+        let registers = storage
+            .inputs
+            .get_mut(start..end)
+            .ok_or("the statistics block is out of the input registers")?;
+        state.stats.fill_registers(registers);
+        state.forces.apply(state.main_unit_id, &mut storage);
+        drop(storage);
+        thread::sleep(REFRESH);
+    }
+    Ok(())
+}
+
+fn metrics_dialogue(
+    stream: TcpStream,
+    state: &SharedState,
+) -> Result<(), Box<dyn Error>> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+        line.clear();
+    }
+    let mut words = request_line.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", state.stats.prometheus())
+        }
+        _ => ("404 Not Found", "try /metrics\n".to_owned()),
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+/// Serves the statistics over HTTP, at `/metrics` on a local port, for
+/// Prometheus to scrape.
+pub fn metrics_server(
+    port: u16,
+    state: &SharedState,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    listener.set_nonblocking(true)?;
+    println!("statistics served on http://127.0.0.1:{}/metrics", port);
+    while !state.must_quit.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // scrapes are short, serve them one at a time
+                stream.set_nonblocking(false)?;
+                if let Err(e) = metrics_dialogue(stream, state) {
+                    eprintln!("{:?}", e);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(200));
+            }
+            Err(e) => Err(e)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_capped() {
+        let stats = Stats::default();
+        for client in 0..MAX_CLIENTS + 5 {
+            stats.client_request(&format!(
+                "10.0.{}.{}",
+                client / 256,
+                client % 256
+            ));
+        }
+        // known clients are still counted separately
+        stats.client_request("10.0.0.0");
+        let rates = stats.client_rates();
+        assert_eq!(rates.len(), MAX_CLIENTS + 1);
+        let count =
+            |name: &str| rates.iter().find(|c| c.0 == name).map(|c| c.1);
+        assert_eq!(count(OTHER_CLIENTS), Some(5));
+        assert_eq!(count("10.0.0.0"), Some(2));
+    }
+
+    #[test]
+    fn labels_escaped() {
+        assert_eq!(escape_label(r#"COM"1\2"#), r#"COM\"1\\2"#);
+        assert_eq!(escape_label("a\nb"), r"a\nb");
+        let stats = Stats::default();
+        stats.client_request(r#"/dev/tty"1"#);
+        assert!(stats.prometheus().contains(
+            r#"modbus_client_requests_total{client="/dev/tty\"1"} 1"#
+        ));
+    }
+}
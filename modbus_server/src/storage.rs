@@ -0,0 +1,629 @@
+use std::{fmt, str::FromStr};
+
+use rmodbus::{server::context::ModbusContext, ErrorKind, VectorTrait};
+use serde::{Deserialize, Serialize};
+
+/// Modbus register map whose sizes are chosen at startup.
+///
+/// `rmodbus::server::storage::ModbusStorage` fixes the number of coils,
+/// discrete inputs, input registers and holding registers at compile time;
+/// this storage behaves the same way but keeps its areas in vectors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModbusSimu {
+    pub coils: Vec<bool>,
+    pub discretes: Vec<bool>,
+    pub inputs: Vec<u16>,
+    pub holdings: Vec<u16>,
+}
+
+impl ModbusSimu {
+    pub fn new(
+        coil_count: usize,
+        discrete_count: usize,
+        input_count: usize,
+        holding_count: usize,
+    ) -> Self {
+        Self {
+            coils: vec![false; coil_count],
+            discretes: vec![false; discrete_count],
+            inputs: vec![0; input_count],
+            holdings: vec![0; holding_count],
+        }
+    }
+
+    /// Writes the size of each area into the first holding registers, as
+    /// far as there are holding registers, so that clients can discover
+    /// them.
+    pub fn advertise_sizes(&mut self) {
+        let sizes = [
+            self.coils.len(),
+            self.discretes.len(),
+            self.inputs.len(),
+            self.holdings.len(),
+        ];
+        for (holding, size) in self.holdings.iter_mut().zip(sizes) {
+            *holding = size as u16;
+        }
+    }
+}
+
+/// Location in the storage, written with the PLC notation used by
+/// `modbus_client`: `%M<n>` coil, `%I<n>` discrete input, `%IW<n>` input
+/// register, `%MW<n>` holding register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Address {
+    Coil(u16),
+    Discrete(u16),
+    Input(u16),
+    Holding(u16),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = if let Some(index) = s.strip_prefix("%MW") {
+            index.parse().map(Address::Holding)
+        } else if let Some(index) = s.strip_prefix("%M") {
+            index.parse().map(Address::Coil)
+        } else if let Some(index) = s.strip_prefix("%IW") {
+            index.parse().map(Address::Input)
+        } else if let Some(index) = s.strip_prefix("%I") {
+            index.parse().map(Address::Discrete)
+        } else {
+            return Err(format!("invalid address {s:?}"));
+        };
+        parsed.map_err(|_| format!("invalid address {s:?}"))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Coil(i) => write!(f, "%M{i}"),
+            Address::Discrete(i) => write!(f, "%I{i}"),
+            Address::Input(i) => write!(f, "%IW{i}"),
+            Address::Holding(i) => write!(f, "%MW{i}"),
+        }
+    }
+}
+
+impl ModbusSimu {
+    /// Reads any location, booleans being 0 or 1.
+    pub fn get(&self, address: Address) -> Result<u16, ErrorKind> {
+        match address {
+            Address::Coil(i) => self.get_coil(i).map(u16::from),
+            Address::Discrete(i) => self.get_discrete(i).map(u16::from),
+            Address::Input(i) => self.get_input(i),
+            Address::Holding(i) => self.get_holding(i),
+        }
+    }
+
+    /// Writes any location, booleans being set by any non-zero value.
+    pub fn set(
+        &mut self,
+        address: Address,
+        value: u16,
+    ) -> Result<(), ErrorKind> {
+        match address {
+            Address::Coil(i) => self.set_coil(i, value != 0),
+            Address::Discrete(i) => self.set_discrete(i, value != 0),
+            Address::Input(i) => self.set_input(i, value),
+            Address::Holding(i) => self.set_holding(i, value),
+        }
+    }
+}
+
+fn range(
+    reg: u16,
+    count: usize,
+    size: usize,
+) -> Result<std::ops::Range<usize>, ErrorKind> {
+    let reg_to = reg as usize + count;
+    if reg_to > size {
+        Err(ErrorKind::OOBContext)
+    } else {
+        Ok(reg as usize..reg_to)
+    }
+}
+
+fn get_regs_as_u8<V: VectorTrait<u8>>(
+    regs: &[u16],
+    reg: u16,
+    count: u16,
+    result: &mut V,
+) -> Result<(), ErrorKind> {
+    for r in &regs[range(reg, count as usize, regs.len())?] {
+        result.extend(&r.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+fn set_regs_from_u8(
+    regs: &mut [u16],
+    reg: u16,
+    values: &[u8],
+) -> Result<(), ErrorKind> {
+    if !values.len().is_multiple_of(2) {
+        return Err(ErrorKind::OOB);
+    }
+    let size = regs.len();
+    for (r, v) in regs[range(reg, values.len() / 2, size)?]
+        .iter_mut()
+        .zip(values.chunks_exact(2))
+    {
+        *r = u16::from_be_bytes([v[0], v[1]]);
+    }
+    Ok(())
+}
+
+fn get_bools_as_u8<V: VectorTrait<u8>>(
+    bools: &[bool],
+    reg: u16,
+    count: u16,
+    result: &mut V,
+) -> Result<(), ErrorKind> {
+    for chunk in bools[range(reg, count as usize, bools.len())?].chunks(8) {
+        let byte = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |byte, (i, b)| byte | (u8::from(*b) << i));
+        result.push(byte)?;
+    }
+    Ok(())
+}
+
+fn get_bools_as_u8_bytes<V: VectorTrait<u8>>(
+    bools: &[bool],
+    reg: u16,
+    count: u16,
+    result: &mut V,
+) -> Result<(), ErrorKind> {
+    for b in &bools[range(reg, count as usize, bools.len())?] {
+        result.push(u8::from(*b))?;
+    }
+    Ok(())
+}
+
+fn set_bools_from_u8(
+    bools: &mut [bool],
+    reg: u16,
+    count: u16,
+    values: &[u8],
+) -> Result<(), ErrorKind> {
+    let size = bools.len();
+    for (i, b) in bools[range(reg, count as usize, size)?]
+        .iter_mut()
+        .enumerate()
+    {
+        let byte = values.get(i / 8).ok_or(ErrorKind::OOB)?;
+        *b = byte >> (i % 8) & 1 == 1;
+    }
+    Ok(())
+}
+
+fn set_bools_from_u8_bytes(
+    bools: &mut [bool],
+    reg: u16,
+    values: &[u8],
+) -> Result<(), ErrorKind> {
+    let size = bools.len();
+    for (b, v) in bools[range(reg, values.len(), size)?]
+        .iter_mut()
+        .zip(values)
+    {
+        *b = *v > 0;
+    }
+    Ok(())
+}
+
+fn get_bulk<T: Copy, V: VectorTrait<T>>(
+    area: &[T],
+    reg: u16,
+    count: u16,
+    result: &mut V,
+) -> Result<(), ErrorKind> {
+    result.extend(&area[range(reg, count as usize, area.len())?])
+}
+
+fn set_bulk<T: Copy>(
+    area: &mut [T],
+    reg: u16,
+    values: &[T],
+) -> Result<(), ErrorKind> {
+    let size = area.len();
+    area[range(reg, values.len(), size)?].copy_from_slice(values);
+    Ok(())
+}
+
+fn get<T: Copy>(area: &[T], reg: u16) -> Result<T, ErrorKind> {
+    area.get(reg as usize).copied().ok_or(ErrorKind::OOBContext)
+}
+
+fn set<T>(area: &mut [T], reg: u16, value: T) -> Result<(), ErrorKind> {
+    *area.get_mut(reg as usize).ok_or(ErrorKind::OOBContext)? = value;
+    Ok(())
+}
+
+fn get_u64(regs: &[u16], reg: u16, count: usize) -> Result<u64, ErrorKind> {
+    Ok(regs[range(reg, count, regs.len())?]
+        .iter()
+        .fold(0, |acc, r| (acc << 16) | u64::from(*r)))
+}
+
+fn set_u64(
+    regs: &mut [u16],
+    reg: u16,
+    count: usize,
+    value: u64,
+) -> Result<(), ErrorKind> {
+    let size = regs.len();
+    for (i, r) in regs[range(reg, count, size)?].iter_mut().rev().enumerate() {
+        *r = (value >> (16 * i)) as u16;
+    }
+    Ok(())
+}
+
+impl ModbusContext for ModbusSimu {
+    fn get_inputs_as_u8<V: VectorTrait<u8>>(
+        &self,
+        reg: u16,
+        count: u16,
+        result: &mut V,
+    ) -> Result<(), ErrorKind> {
+        get_regs_as_u8(&self.inputs, reg, count, result)
+    }
+
+    fn get_holdings_as_u8<V: VectorTrait<u8>>(
+        &self,
+        reg: u16,
+        count: u16,
+        result: &mut V,
+    ) -> Result<(), ErrorKind> {
+        get_regs_as_u8(&self.holdings, reg, count, result)
+    }
+
+    fn set_inputs_from_u8(
+        &mut self,
+        reg: u16,
+        values: &[u8],
+    ) -> Result<(), ErrorKind> {
+        set_regs_from_u8(&mut self.inputs, reg, values)
+    }
+
+    fn set_holdings_from_u8(
+        &mut self,
+        reg: u16,
+        values: &[u8],
+    ) -> Result<(), ErrorKind> {
+        set_regs_from_u8(&mut self.holdings, reg, values)
+    }
+
+    fn get_coils_as_u8<V: VectorTrait<u8>>(
+        &self,
+        reg: u16,
+        count: u16,
+        result: &mut V,
+    ) -> Result<(), ErrorKind> {
+        get_bools_as_u8(&self.coils, reg, count, result)
+    }
+
+    fn get_coils_as_u8_bytes<V: VectorTrait<u8>>(
+        &self,
+        reg: u16,
+        count: u16,
+        result: &mut V,
+    ) -> Result<(), ErrorKind> {
+        get_bools_as_u8_bytes(&self.coils, reg, count, result)
+    }
+
+    fn get_discretes_as_u8<V: VectorTrait<u8>>(
+        &self,
+        reg: u16,
+        count: u16,
+        result: &mut V,
+    ) -> Result<(), ErrorKind> {
+        get_bools_as_u8(&self.discretes, reg, count, result)
+    }
+
+    fn get_discretes_as_u8_bytes<V: VectorTrait<u8>>(
+        &self,
+        reg: u16,
+        count: u16,
+        result: &mut V,
+    ) -> Result<(), ErrorKind> {
+        get_bools_as_u8_bytes(&self.discretes, reg, count, result)
+    }
+
+    fn set_coils_from_u8(
+        &mut self,
+        reg: u16,
+        count: u16,
+        values: &[u8],
+    ) -> Result<(), ErrorKind> {
+        set_bools_from_u8(&mut self.coils, reg, count, values)
+    }
+
+    fn set_discretes_from_u8(
+        &mut self,
+        reg: u16,
+        count: u16,
+        values: &[u8],
+    ) -> Result<(), ErrorKind> {
+        set_bools_from_u8(&mut self.discretes, reg, count, values)
+    }
+
+    fn set_coils_from_u8_bytes(
+        &mut self,
+        reg: u16,
+        values: &[u8],
+    ) -> Result<(), ErrorKind> {
+        set_bools_from_u8_bytes(&mut self.coils, reg, values)
+    }
+
+    fn set_discretes_from_u8_bytes(
+        &mut self,
+        reg: u16,
+        values: &[u8],
+    ) -> Result<(), ErrorKind> {
+        set_bools_from_u8_bytes(&mut self.discretes, reg, values)
+    }
+
+    fn get_coils_bulk<V: VectorTrait<bool>>(
+        &self,
+        reg: u16,
+        count: u16,
+        result: &mut V,
+    ) -> Result<(), ErrorKind> {
+        get_bulk(&self.coils, reg, count, result)
+    }
+
+    fn get_discretes_bulk<V: VectorTrait<bool>>(
+        &self,
+        reg: u16,
+        count: u16,
+        result: &mut V,
+    ) -> Result<(), ErrorKind> {
+        get_bulk(&self.discretes, reg, count, result)
+    }
+
+    fn get_inputs_bulk<V: VectorTrait<u16>>(
+        &self,
+        reg: u16,
+        count: u16,
+        result: &mut V,
+    ) -> Result<(), ErrorKind> {
+        get_bulk(&self.inputs, reg, count, result)
+    }
+
+    fn get_holdings_bulk<V: VectorTrait<u16>>(
+        &self,
+        reg: u16,
+        count: u16,
+        result: &mut V,
+    ) -> Result<(), ErrorKind> {
+        get_bulk(&self.holdings, reg, count, result)
+    }
+
+    fn set_coils_bulk(
+        &mut self,
+        reg: u16,
+        values: &[bool],
+    ) -> Result<(), ErrorKind> {
+        set_bulk(&mut self.coils, reg, values)
+    }
+
+    fn set_discretes_bulk(
+        &mut self,
+        reg: u16,
+        values: &[bool],
+    ) -> Result<(), ErrorKind> {
+        set_bulk(&mut self.discretes, reg, values)
+    }
+
+    fn set_inputs_bulk(
+        &mut self,
+        reg: u16,
+        values: &[u16],
+    ) -> Result<(), ErrorKind> {
+        set_bulk(&mut self.inputs, reg, values)
+    }
+
+    fn set_holdings_bulk(
+        &mut self,
+        reg: u16,
+        values: &[u16],
+    ) -> Result<(), ErrorKind> {
+        set_bulk(&mut self.holdings, reg, values)
+    }
+
+    fn get_coil(&self, reg: u16) -> Result<bool, ErrorKind> {
+        get(&self.coils, reg)
+    }
+
+    fn get_discrete(&self, reg: u16) -> Result<bool, ErrorKind> {
+        get(&self.discretes, reg)
+    }
+
+    fn get_input(&self, reg: u16) -> Result<u16, ErrorKind> {
+        get(&self.inputs, reg)
+    }
+
+    fn get_holding(&self, reg: u16) -> Result<u16, ErrorKind> {
+        get(&self.holdings, reg)
+    }
+
+    fn set_coil(&mut self, reg: u16, value: bool) -> Result<(), ErrorKind> {
+        set(&mut self.coils, reg, value)
+    }
+
+    fn set_discrete(
+        &mut self,
+        reg: u16,
+        value: bool,
+    ) -> Result<(), ErrorKind> {
+        set(&mut self.discretes, reg, value)
+    }
+
+    fn set_input(&mut self, reg: u16, value: u16) -> Result<(), ErrorKind> {
+        set(&mut self.inputs, reg, value)
+    }
+
+    fn set_holding(&mut self, reg: u16, value: u16) -> Result<(), ErrorKind> {
+        set(&mut self.holdings, reg, value)
+    }
+
+    fn get_inputs_as_u32(&self, reg: u16) -> Result<u32, ErrorKind> {
+        Ok(get_u64(&self.inputs, reg, 2)? as u32)
+    }
+
+    fn get_holdings_as_u32(&self, reg: u16) -> Result<u32, ErrorKind> {
+        Ok(get_u64(&self.holdings, reg, 2)? as u32)
+    }
+
+    fn set_inputs_from_u32(
+        &mut self,
+        reg: u16,
+        value: u32,
+    ) -> Result<(), ErrorKind> {
+        set_u64(&mut self.inputs, reg, 2, value.into())
+    }
+
+    fn set_holdings_from_u32(
+        &mut self,
+        reg: u16,
+        value: u32,
+    ) -> Result<(), ErrorKind> {
+        set_u64(&mut self.holdings, reg, 2, value.into())
+    }
+
+    fn get_inputs_as_u64(&self, reg: u16) -> Result<u64, ErrorKind> {
+        get_u64(&self.inputs, reg, 4)
+    }
+
+    fn get_holdings_as_u64(&self, reg: u16) -> Result<u64, ErrorKind> {
+        get_u64(&self.holdings, reg, 4)
+    }
+
+    fn set_inputs_from_u64(
+        &mut self,
+        reg: u16,
+        value: u64,
+    ) -> Result<(), ErrorKind> {
+        set_u64(&mut self.inputs, reg, 4, value)
+    }
+
+    fn set_holdings_from_u64(
+        &mut self,
+        reg: u16,
+        value: u64,
+    ) -> Result<(), ErrorKind> {
+        set_u64(&mut self.holdings, reg, 4, value)
+    }
+
+    fn get_inputs_as_f32(&self, reg: u16) -> Result<f32, ErrorKind> {
+        Ok(f32::from_bits(self.get_inputs_as_u32(reg)?))
+    }
+
+    fn get_holdings_as_f32(&self, reg: u16) -> Result<f32, ErrorKind> {
+        Ok(f32::from_bits(self.get_holdings_as_u32(reg)?))
+    }
+
+    fn set_inputs_from_f32(
+        &mut self,
+        reg: u16,
+        value: f32,
+    ) -> Result<(), ErrorKind> {
+        self.set_inputs_from_u32(reg, value.to_bits())
+    }
+
+    fn set_holdings_from_f32(
+        &mut self,
+        reg: u16,
+        value: f32,
+    ) -> Result<(), ErrorKind> {
+        self.set_holdings_from_u32(reg, value.to_bits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_up_to_the_end() {
+        let mut storage = ModbusSimu::new(0, 0, 4, 10);
+        storage.set_holdings_bulk(8, &[1, 2]).unwrap();
+        let mut values = Vec::new();
+        storage.get_holdings_bulk(7, 3, &mut values).unwrap();
+        assert_eq!(values, [0, 1, 2]);
+        let mut bytes = Vec::new();
+        storage.get_inputs_as_u8(0, 4, &mut bytes).unwrap();
+        assert_eq!(bytes, [0; 8]);
+        storage.set_holding(9, 7).unwrap();
+        assert_eq!(storage.get_holding(9), Ok(7));
+        storage.set_holdings_from_u32(8, 0x0102_0304).unwrap();
+        assert_eq!(storage.get_holdings_as_u32(8), Ok(0x0102_0304));
+    }
+
+    #[test]
+    fn registers_one_past_the_end() {
+        let mut storage = ModbusSimu::new(0, 0, 4, 10);
+        let mut values = Vec::new();
+        assert!(storage.get_holdings_bulk(8, 3, &mut values).is_err());
+        assert!(storage.set_holdings_bulk(9, &[1, 2]).is_err());
+        assert!(storage.get_inputs_as_u8(1, 4, &mut Vec::new()).is_err());
+        assert!(storage.set_holdings_from_u8(9, &[0, 1, 0, 2]).is_err());
+        assert!(storage.get_holding(10).is_err());
+        assert!(storage.set_holding(10, 1).is_err());
+        assert!(storage.get_holdings_as_u32(9).is_err());
+        assert!(storage.set_holdings_from_u64(7, 1).is_err());
+        assert!(storage.get(Address::Input(4)).is_err());
+        // nothing was written by the refused requests
+        assert_eq!(storage.holdings, [0; 10]);
+    }
+
+    #[test]
+    fn bits_up_to_the_end() {
+        let mut storage = ModbusSimu::new(10, 9, 0, 0);
+        // coils 8 and 9 from the low bits of one byte
+        storage.set_coils_from_u8(8, 2, &[0b11]).unwrap();
+        let mut bytes = Vec::new();
+        storage.get_coils_as_u8(0, 10, &mut bytes).unwrap();
+        assert_eq!(bytes, [0, 0b11]);
+        storage.set_discrete(8, true).unwrap();
+        assert_eq!(storage.get(Address::Discrete(8)), Ok(1));
+        let mut bytes = Vec::new();
+        storage.get_discretes_as_u8_bytes(7, 2, &mut bytes).unwrap();
+        assert_eq!(bytes, [0, 1]);
+    }
+
+    #[test]
+    fn bits_one_past_the_end() {
+        let mut storage = ModbusSimu::new(10, 9, 0, 0);
+        assert!(storage.get_coils_as_u8(1, 10, &mut Vec::new()).is_err());
+        assert!(storage.set_coils_from_u8(9, 2, &[0b11]).is_err());
+        assert!(storage.set_coils_bulk(9, &[true, true]).is_err());
+        assert!(storage.get_discrete(9).is_err());
+        assert!(storage.set(Address::Coil(10), 1).is_err());
+        assert_eq!(storage.coils, [false; 10]);
+    }
+
+    #[test]
+    fn empty_areas() {
+        let mut storage = ModbusSimu::new(0, 0, 0, 0);
+        assert!(storage.get_input(0).is_err());
+        assert!(storage.get_inputs_as_u8(0, 1, &mut Vec::new()).is_err());
+        assert!(storage.set_inputs_bulk(0, &[1]).is_err());
+        assert!(storage.get_coils_as_u8(0, 1, &mut Vec::new()).is_err());
+        assert!(storage.get(Address::Discrete(0)).is_err());
+        assert!(storage.set(Address::Holding(0), 1).is_err());
+        // the last address of the protocol does not overflow
+        assert!(storage
+            .get_holdings_bulk(u16::MAX, 1, &mut Vec::new())
+            .is_err());
+        // sizes are advertised as far as there are holding registers
+        storage.advertise_sizes();
+        assert!(storage.holdings.is_empty());
+    }
+}
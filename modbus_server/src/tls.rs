@@ -0,0 +1,214 @@
+use std::{
+    error::Error,
+    io::Write,
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use serde::Deserialize;
+use x509_parser::{
+    der_parser::asn1_rs::Utf8String,
+    prelude::{FromDer, X509Certificate},
+};
+
+use crate::{
+    accept_clients, access::Permission, faults::Transport, modbus_dialogue,
+    SharedState, Timeouts,
+};
+
+/// Extension of client certificates holding their role, as defined by the
+/// Modbus/TCP Security specification.
+const ROLE_OID: &str = "1.3.6.1.4.1.50316.802.1";
+
+/// Modbus/TCP Security: Modbus over TLS with mutual authentication.
+///
+/// Clients must present a certificate issued by `ca`; the role found in it
+/// selects their permission, which is further restricted by the access
+/// lists. Clients whose role is missing or unknown are refused. Scripts
+/// like `scripts/gen_certs.sh` generate suitable certificates.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// port of the TLS server, usually 802; disabled if not set
+    pub port: Option<u16>,
+    /// certificate chain and private key of the server, PEM encoded
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    /// authority which issued the client certificates
+    pub ca: Option<String>,
+    pub read_write_roles: Vec<String>,
+    pub read_only_roles: Vec<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            port: None,
+            cert: None,
+            key: None,
+            ca: None,
+            read_write_roles: vec!["ReadWrite".to_owned()],
+            read_only_roles: vec!["ReadOnly".to_owned()],
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let files = [&self.cert, &self.key, &self.ca];
+        if self.port.is_some() && files.iter().any(|file| file.is_none()) {
+            Err("TLS needs a certificate, a private key and a CA \
+                 (--tls-cert, --tls-key, --tls-ca)")?
+        }
+        Ok(())
+    }
+
+    fn permission(&self, role: &str) -> Option<Permission> {
+        if self.read_write_roles.iter().any(|r| r == role) {
+            Some(Permission::ReadWrite)
+        } else if self.read_only_roles.iter().any(|r| r == role) {
+            Some(Permission::ReadOnly)
+        } else {
+            None
+        }
+    }
+
+    fn server_config(&self) -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+        let (Some(cert), Some(key), Some(ca)) =
+            (&self.cert, &self.key, &self.ca)
+        else {
+            Err("incomplete TLS configuration")?
+        };
+        let cannot_read = |path: &str, e| format!("cannot read {path:?}: {e}");
+        let certs = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| cannot_read(cert, e))?;
+        let key = PrivateKeyDer::from_pem_file(key)
+            .map_err(|e| cannot_read(key, e))?;
+        let mut roots = RootCertStore::empty();
+        for authority in CertificateDer::pem_file_iter(ca)
+            .map_err(|e| cannot_read(ca, e))?
+        {
+            roots.add(authority.map_err(|e| cannot_read(ca, e))?)?;
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = WebPkiClientVerifier::builder_with_provider(
+            Arc::new(roots),
+            Arc::clone(&provider),
+        )
+        .build()?;
+        // TLS 1.2 at least, as required by the specification
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// Role stored in a client certificate, if any.
+fn certificate_role(
+    certificate: &CertificateDer,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let (_, certificate) = X509Certificate::from_der(certificate)
+        .map_err(|e| format!("invalid client certificate: {e}"))?;
+    let Some(extension) = certificate
+        .extensions()
+        .iter()
+        .find(|extension| extension.oid.to_id_string() == ROLE_OID)
+    else {
+        return Ok(None);
+    };
+    // the extension value is a DER encoded UTF8String
+    let (_, role) = Utf8String::from_der(extension.value)
+        .map_err(|e| format!("invalid role extension: {e}"))?;
+    Ok(Some(role.string()))
+}
+
+/// Authenticates a client, then serves it like a plain TCP client.
+fn tls_dialogue(
+    stream: TcpStream,
+    server_config: Arc<ServerConfig>,
+    config: &TlsConfig,
+    timeouts: Timeouts,
+    permission: Permission,
+    connection: u64,
+    state: &SharedState,
+) -> Result<(), Box<dyn Error>> {
+    let socket = stream.try_clone()?;
+    // the handshake must not stall longer than a request would
+    socket.set_read_timeout(Some(timeouts.read))?;
+    let mut tls =
+        StreamOwned::new(ServerConnection::new(server_config)?, stream);
+    while tls.conn.is_handshaking() {
+        tls.conn
+            .complete_io(&mut tls.sock)
+            .map_err(|e| format!("TLS handshake failed: {e}"))?;
+    }
+    let certificate = tls
+        .conn
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .ok_or("no client certificate")?;
+    let role = certificate_role(certificate)?;
+    let Some(role_permission) =
+        role.as_deref().and_then(|role| config.permission(role))
+    else {
+        tls.conn.send_close_notify();
+        let _ = tls.flush();
+        Err(format!("client refused, role {role:?} is not allowed"))?
+    };
+    // the most restrictive of the address and role permissions applies
+    let permission = permission.min(role_permission);
+    println!(
+        "client {:?} authenticated with role {:?} ({:?})",
+        socket.peer_addr()?,
+        role.unwrap_or_default(),
+        permission
+    );
+    let faults = state.faults.session(Transport::Tls, connection);
+    let result = modbus_dialogue(
+        &mut tls, &socket, timeouts, permission, faults, state,
+    );
+    tls.conn.send_close_notify();
+    let _ = tls.flush();
+    result
+}
+
+/// Serves Modbus/TCP Security clients, with a thread for each one.
+pub fn modbus_tls_server(
+    config: TlsConfig,
+    read_timeout: Duration,
+    state: Arc<SharedState>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(port) = config.port else {
+        return Ok(());
+    };
+    let server_config = config.server_config()?;
+    let timeouts = Timeouts::new(read_timeout, &state.access);
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    println!(
+        "modbus tcp security server waiting for connections on port '{}'",
+        port
+    );
+    accept_clients(listener, &state, {
+        let state = Arc::clone(&state);
+        move |stream, permission, connection| {
+            tls_dialogue(
+                stream,
+                Arc::clone(&server_config),
+                &config,
+                timeouts,
+                permission,
+                connection,
+                &state,
+            )
+        }
+    })
+}
@@ -0,0 +1,232 @@
+use std::{
+    error::Error,
+    net::Ipv4Addr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use rmodbus::ModbusProto;
+use socket2::SockRef;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinSet,
+    time::{self, timeout},
+};
+
+use crate::{
+    access::Permission,
+    admit, classify_frame,
+    faults::{Outcome, Transport},
+    frame::MAX_TCP_ADU_LEN,
+    mbap_body_length, Received, SharedState, Timeouts, POLL,
+};
+
+type AsyncError = Box<dyn Error + Send + Sync>;
+
+/// Reads one MBAP-framed request, like `recv_request_bytes`.
+async fn recv_request_bytes<'b>(
+    stream: &mut TcpStream,
+    buffer: &'b mut [u8; MAX_TCP_ADU_LEN],
+    timeouts: Timeouts,
+    state: &SharedState,
+) -> Result<Received<'b>, AsyncError> {
+    let first = stream.read(&mut buffer[..7]);
+    let count = match timeouts.idle {
+        Some(idle) => timeout(idle, first)
+            .await
+            .map_err(|_| format!("idle for {} ms", idle.as_millis()))??,
+        None => first.await?,
+    };
+    if count == 0 {
+        return Ok(Received::Closed); // EOF
+    }
+    let mut drained = Vec::new();
+    let rest = async {
+        stream.read_exact(&mut buffer[count..7]).await?;
+        let body_length = mbap_body_length(&buffer[..7]);
+        let body = if 7 + body_length > buffer.len() {
+            drained.resize(body_length, 0);
+            &mut drained[..]
+        } else {
+            &mut buffer[7..7 + body_length]
+        };
+        stream.read_exact(body).await?;
+        Ok::<_, AsyncError>(body_length)
+    };
+    let body_length = timeout(timeouts.read, rest).await.map_err(|_| {
+        format!("incomplete frame after {} ms", timeouts.read.as_millis())
+    })??;
+    Ok(classify_frame(buffer, body_length, &drained, state))
+}
+
+/// Serves one client until it disconnects or the server stops, with the
+/// same behaviour as the threaded `modbus_dialogue`.
+async fn modbus_dialogue(
+    mut stream: TcpStream,
+    timeouts: Timeouts,
+    permission: Permission,
+    connection: u64,
+    state: &SharedState,
+    mut stop: watch::Receiver<bool>,
+) -> Result<(), AsyncError> {
+    let mut faults = state.faults.session(Transport::Tcp, connection);
+    let mut capture = match &state.capture {
+        Some(capture) => {
+            capture.tcp_session(stream.peer_addr()?, stream.local_addr()?)
+        }
+        None => None,
+    };
+    let client = stream.peer_addr()?.ip().to_string();
+    let mut buffer = [0; MAX_TCP_ADU_LEN];
+    loop {
+        let request =
+            recv_request_bytes(&mut stream, &mut buffer, timeouts, state);
+        let received = tokio::select! {
+            received = request => received?,
+            _ = stop.wait_for(|stopped| *stopped) => break,
+        };
+        let bytes = match received {
+            Received::Request(bytes) => bytes,
+            Received::Rejected(request, response) => {
+                state.stats.client_request(&client);
+                state.stats.invalid_frame();
+                if let Some(capture) = &mut capture {
+                    capture.request(&request);
+                    capture.response(&response);
+                }
+                stream.write_all(&response).await?;
+                continue;
+            }
+            Received::Skipped(frame) => {
+                state.stats.invalid_frame();
+                if let Some(capture) = &mut capture {
+                    capture.request(&frame);
+                }
+                continue;
+            }
+            Received::Closed => break,
+        };
+        state.stats.client_request(&client);
+        if let Some(capture) = &mut capture {
+            capture.request(bytes);
+        }
+        let drawn = faults.draw();
+        if !drawn.delay.is_zero() {
+            time::sleep(drawn.delay).await;
+        }
+        let outcome =
+            match drawn.apply(state, bytes, ModbusProto::TcpUdp, permission) {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    // a bad frame does not end the session
+                    eprintln!("invalid tcp frame {:02x?}: {:?}", bytes, e);
+                    continue;
+                }
+            };
+        match outcome {
+            Outcome::Respond(response) => {
+                if let Some(capture) = &mut capture {
+                    capture.response(&response);
+                }
+                stream.write_all(&response).await?
+            }
+            Outcome::Silent => {}
+            Outcome::Reset => {
+                if let Some(capture) = &mut capture {
+                    capture.reset();
+                }
+                // closing with a zero linger time sends a RST
+                SockRef::from(&stream).set_linger(Some(Duration::ZERO))?;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn serve(
+    tcp_port: u16,
+    timeouts: Timeouts,
+    state: Arc<SharedState>,
+) -> Result<(), AsyncError> {
+    let listener =
+        TcpListener::bind((Ipv4Addr::UNSPECIFIED, tcp_port)).await?;
+    println!(
+        "modbus tcp server (async) waiting for connections on port '{}'",
+        tcp_port
+    );
+    // turn must_quit into a notification every task can wait for
+    let (stopping, stop) = watch::channel(false);
+    tokio::spawn({
+        let state = Arc::clone(&state);
+        async move {
+            let mut poll = time::interval(POLL);
+            while !state.must_quit.load(Ordering::Relaxed) {
+                poll.tick().await;
+            }
+            let _ = stopping.send(true);
+        }
+    });
+    let mut clients = JoinSet::new();
+    // numbers the fault sessions
+    let mut connections = 0;
+    let mut accepting = stop.clone();
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    state.must_quit.store(true, Ordering::Relaxed);
+                    Err(e)?
+                }
+            },
+            _ = accepting.wait_for(|stopped| *stopped) => break,
+        };
+        // forget the clients which already left
+        while clients.try_join_next().is_some() {}
+        let Some((permission, slot)) = admit(&state, addr) else {
+            continue;
+        };
+        let connection = connections;
+        connections += 1;
+        let state = Arc::clone(&state);
+        let stop = stop.clone();
+        clients.spawn(async move {
+            let _slot = slot;
+            if let Err(e) = modbus_dialogue(
+                stream, timeouts, permission, connection, &state, stop,
+            )
+            .await
+            {
+                state.stats.connection_error();
+                eprintln!("{:?}", e);
+            }
+            println!("client {:?} disconnected", addr);
+        });
+    }
+    // let the clients notice the stop and close their connection
+    while clients.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Serves Modbus TCP on a tokio runtime instead of a thread per client.
+///
+/// Requests are processed exactly as by `modbus_tcp_server`; `must_quit`
+/// is polled by a single task which then stops the accept loop and every
+/// connection at once.
+pub fn modbus_tcp_server_async(
+    tcp_port: u16,
+    read_timeout: Duration,
+    state: Arc<SharedState>,
+) -> Result<(), Box<dyn Error>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let timeouts = Timeouts::new(read_timeout, &state.access);
+    runtime
+        .block_on(serve(tcp_port, timeouts, state))
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
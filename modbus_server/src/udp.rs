@@ -0,0 +1,73 @@
+use std::{
+    error::Error,
+    io::ErrorKind,
+    net::{Ipv4Addr, UdpSocket},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use rmodbus::ModbusProto;
+
+use crate::{
+    faults::{Outcome, Transport},
+    frame::MAX_TCP_ADU_LEN,
+    SharedState,
+};
+
+/// Serves Modbus requests received as UDP datagrams.
+///
+/// Each datagram carries exactly one MBAP-framed request; there is no
+/// connection state, so every datagram is answered to its sender. Senders
+/// are checked against the access lists like TCP clients.
+pub fn modbus_udp_server(
+    udp_port: u16,
+    state: Arc<SharedState>,
+) -> Result<(), Box<dyn Error>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, udp_port))?;
+    // wake up regularly to notice must_quit
+    socket.set_read_timeout(Some(Duration::from_millis(200)))?;
+    println!(
+        "modbus udp server waiting for datagrams on port '{}'",
+        udp_port
+    );
+    let local_addr = socket.local_addr()?;
+    let mut faults = state.faults.session(Transport::Udp, 0);
+    let mut buffer = [0; MAX_TCP_ADU_LEN];
+    while !state.must_quit.load(Ordering::Relaxed) {
+        let (length, addr) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => Err(e)?,
+        };
+        let Some(permission) = state.access.permission(addr.ip()) else {
+            eprintln!("datagram from {:?} refused", addr);
+            continue;
+        };
+        let request = &buffer[..length];
+        state.stats.client_request(&addr.ip().to_string());
+        if let Some(capture) = &state.capture {
+            capture.udp(addr, local_addr, request);
+        }
+        match faults.serve(&state, request, ModbusProto::TcpUdp, permission) {
+            Ok(Outcome::Respond(response)) => {
+                if let Some(capture) = &state.capture {
+                    capture.udp(local_addr, addr, &response);
+                }
+                socket.send_to(&response, addr)?;
+            }
+            // there is no connection to reset
+            Ok(Outcome::Silent | Outcome::Reset) => {}
+            Err(e) => {
+                eprintln!("invalid udp datagram from {:?}: {:?}", addr, e)
+            }
+        }
+    }
+    Ok(())
+}